@@ -0,0 +1,68 @@
+use crate::config::{self, CONFIG_FILE};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// One audit record per `sync --audit-dir` run.
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    /// RFC 3339 start time of the run.
+    pub timestamp: String,
+    pub tool_version: &'static str,
+    /// SHA-256 of `config.json` and any per-submodule files as they were on
+    /// disk for this run.
+    pub config_hash: String,
+    pub submodules: Vec<AuditEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditEntry {
+    pub name: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// `--itemize-changes` lines from the pre-sync dry-run.
+    pub planned_changes: Vec<String>,
+    pub outcome: String,
+    pub exit_code: Option<i32>,
+}
+
+/// Hex-encoded SHA-256 of the config file in `config_dir`, if any, followed
+/// by the per-submodule files in name order when that layout is in use.
+pub fn config_hash(config_dir: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    match fs::read(config_dir.join(CONFIG_FILE)) {
+        Ok(contents) => hasher.update(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    for path in config::submodule_files(config_dir)?.unwrap_or_default() {
+        hasher.update(fs::read(path)?);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writes `record` to a new timestamped file in `audit_dir` and marks it
+/// read-only. Existing records are never overwritten.
+pub fn write_record(audit_dir: &Path, record: &AuditRecord) -> io::Result<PathBuf> {
+    fs::create_dir_all(audit_dir)?;
+    let stamp = chrono::Local::now().format("%Y%m%dT%H%M%S%.3f");
+    let path = audit_dir.join(format!("sync-{}.json", stamp));
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    serde_json::to_writer_pretty(&mut file, record)?;
+    file.write_all(b"\n")?;
+
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&path, permissions)?;
+    Ok(path)
+}
@@ -0,0 +1,103 @@
+use crate::config::ArchiveFlags;
+use crate::rsync::{self, RsyncCommand};
+use crate::sync;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The flag combinations `bench` compares.
+const VARIANTS: [&[&str]; 4] = [
+    &["--whole-file"],
+    &["--no-whole-file"],
+    &["--whole-file", "--compress"],
+    &["--no-whole-file", "--compress"],
+];
+
+/// A scratch directory removed when dropped, so the synthetic data is
+/// cleaned up even when a run fails.
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Syncs a generated dataset of `files` files of `file_size` bytes under
+/// each flag combination and prints how long a full copy into an empty
+/// target and an update after touching every file took.
+pub fn bench(files: usize, file_size: u64) -> io::Result<()> {
+    rsync::check_available()?;
+    let scratch = ScratchDir(
+        std::env::temp_dir().join(format!("monorepo-agent-bench-{}", std::process::id())),
+    );
+    let source = scratch.0.join("source");
+    println!(
+        "Generating {} file(s) of {} in {:?}",
+        files,
+        sync::format_size(file_size),
+        source
+    );
+    generate(&source, files, file_size, 0)?;
+
+    println!("{:<34} {:>10} {:>10}", "flags", "copy", "update");
+    for (index, flags) in VARIANTS.iter().enumerate() {
+        let target = scratch.0.join(format!("target-{}", index));
+        let copy = timed_sync(&source, &target, flags)?;
+        generate(&source, files, file_size, index as u64 + 1)?;
+        let update = timed_sync(&source, &target, flags)?;
+        println!(
+            "{:<34} {:>9.3}s {:>9.3}s",
+            flags.join(" "),
+            copy.as_secs_f64(),
+            update.as_secs_f64()
+        );
+        if target.exists() {
+            fs::remove_dir_all(&target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `files` files of pseudo-random, poorly compressible content into
+/// `dir`. A different `round` rewrites a small block at the start of each
+/// file, leaving the rest as it was for rsync's delta algorithm to reuse.
+fn generate(dir: &Path, files: usize, file_size: u64, round: u64) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for index in 0..files {
+        let mut state = index as u64 + 1;
+        let mut contents: Vec<u8> = (0..file_size)
+            .map(|_| {
+                // xorshift64, good enough to defeat compression.
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let changed = contents.len().min(64);
+        contents[..changed].fill(round as u8);
+        fs::write(dir.join(format!("file-{:05}.bin", index)), contents)?;
+    }
+    Ok(())
+}
+
+/// Runs one rsync from `source` into `target` with `flags` and returns how
+/// long it took.
+fn timed_sync(source: &Path, target: &Path, flags: &[&str]) -> io::Result<Duration> {
+    let mut rsync_cmd = RsyncCommand::copy(&ArchiveFlags::default(), source, target, false);
+    for flag in flags {
+        rsync_cmd.arg(*flag);
+    }
+    let started = Instant::now();
+    let (status, _) = rsync::run_captured(&rsync_cmd)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Benchmark rsync ({}) exited with {}",
+            flags.join(" "),
+            status
+        )));
+    }
+    Ok(started.elapsed())
+}
@@ -0,0 +1,1256 @@
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command as ProcessCommand, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Name of the configuration folder created at the monorepo root.
+pub const CONFIG_DIR: &str = ".monorepo";
+/// Name of the configuration file inside `CONFIG_DIR`.
+pub const CONFIG_FILE: &str = "config.json";
+/// Optional folder inside `CONFIG_DIR` holding one `<name>.json` file per
+/// submodule. When it exists, submodules are saved there instead of in
+/// `config.json`.
+pub const SUBMODULES_DIR: &str = "submodules";
+/// Schema version `save_config` writes. Configs without a `version` field are
+/// version 0.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Set by `--config-readonly`: nothing may write the config in this run.
+static READONLY: AtomicBool = AtomicBool::new(false);
+
+/// Forbids every config write for the rest of the process.
+pub fn set_readonly() {
+    READONLY.store(true, Ordering::Relaxed);
+}
+
+/// Fails when `--config-readonly` is set. Commands that change the config
+/// call this before doing anything, and `save_config` checks it again.
+pub fn ensure_writable() -> io::Result<()> {
+    if READONLY.load(Ordering::Relaxed) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "The config is read-only (--config-readonly); refusing to modify it",
+        ));
+    }
+    Ok(())
+}
+
+/// Sync rules for a single submodule of the monorepo.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubmoduleConfig {
+    pub name: String,
+    /// Source path, relative to the monorepo root.
+    pub path: String,
+    /// Target directory, overriding the sibling `../<name>`. Relative paths
+    /// are resolved against the monorepo root; an rsync remote spec such as
+    /// `user@host:/srv/app` syncs over SSH.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    /// Shell command, run in the monorepo root once per sync, whose trimmed
+    /// stdout is used as `target`. Other commands show the command instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_command: Option<String>,
+    /// Subdirectory of the target the source contents are synced into, so
+    /// several submodules can fill different folders of one target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_subdir: Option<PathBuf>,
+    /// Set to `false` to never delete extra files from this submodule's
+    /// target, whatever the run does for the others.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete: Option<bool>,
+    /// Extra arguments appended to this submodule's rsync command, such as
+    /// `--checksum`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_rsync_args: Option<Vec<String>>,
+    /// I/O timeout in seconds for this submodule's rsync, overriding the
+    /// run's `--timeout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Shell command run in the source directory before this submodule's
+    /// rsync; if it fails, the submodule is not synced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_sync: Option<String>,
+    /// Shell command run in the target directory after a successful rsync.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_sync: Option<String>,
+    /// Shell command run in the target directory after `post_sync`, such as a
+    /// smoke test; if it fails, the submodule counts as failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validate_command: Option<String>,
+    /// Shell command run in the target directory after every successful
+    /// rsync, even when `post_sync` or `validate_command` failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cleanup_command: Option<String>,
+    /// Template whose settings fill in this submodule's unset fields. An
+    /// empty list still counts as set, overriding the template's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+    /// Free-form metadata for external tooling (owner, tickets, tags). It is
+    /// kept on save but never interpreted by the tool.
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub meta: serde_json::Value,
+}
+
+/// Shared settings that submodules can inherit through `extends`: every
+/// optional `SubmoduleConfig` field except the target, which is unique to
+/// each submodule, and `meta`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubmoduleTemplate {
+    /// Another template to inherit unset fields from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_subdir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_rsync_args: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_sync: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_sync: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validate_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cleanup_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+}
+
+impl SubmoduleTemplate {
+    /// Fills the fields this template leaves unset from `parent`.
+    fn inherit_from(&mut self, parent: &SubmoduleTemplate) {
+        inherit(&mut self.target_subdir, &parent.target_subdir);
+        inherit(&mut self.delete, &parent.delete);
+        inherit(&mut self.extra_rsync_args, &parent.extra_rsync_args);
+        inherit(&mut self.timeout_secs, &parent.timeout_secs);
+        inherit(&mut self.pre_sync, &parent.pre_sync);
+        inherit(&mut self.post_sync, &parent.post_sync);
+        inherit(&mut self.validate_command, &parent.validate_command);
+        inherit(&mut self.cleanup_command, &parent.cleanup_command);
+        inherit(&mut self.include, &parent.include);
+        inherit(&mut self.exclude, &parent.exclude);
+    }
+
+    /// Fills the fields `submodule` leaves unset from this template.
+    fn apply_to(&self, submodule: &mut SubmoduleConfig) {
+        inherit(&mut submodule.target_subdir, &self.target_subdir);
+        inherit(&mut submodule.delete, &self.delete);
+        inherit(&mut submodule.extra_rsync_args, &self.extra_rsync_args);
+        inherit(&mut submodule.timeout_secs, &self.timeout_secs);
+        inherit(&mut submodule.pre_sync, &self.pre_sync);
+        inherit(&mut submodule.post_sync, &self.post_sync);
+        inherit(&mut submodule.validate_command, &self.validate_command);
+        inherit(&mut submodule.cleanup_command, &self.cleanup_command);
+        inherit(&mut submodule.include, &self.include);
+        inherit(&mut submodule.exclude, &self.exclude);
+    }
+
+    /// Unsets the fields of `submodule` that hold this template's value.
+    fn remove_from(&self, submodule: &mut SubmoduleConfig) {
+        uninherit(&mut submodule.target_subdir, &self.target_subdir);
+        uninherit(&mut submodule.delete, &self.delete);
+        uninherit(&mut submodule.extra_rsync_args, &self.extra_rsync_args);
+        uninherit(&mut submodule.timeout_secs, &self.timeout_secs);
+        uninherit(&mut submodule.pre_sync, &self.pre_sync);
+        uninherit(&mut submodule.post_sync, &self.post_sync);
+        uninherit(&mut submodule.validate_command, &self.validate_command);
+        uninherit(&mut submodule.cleanup_command, &self.cleanup_command);
+        uninherit(&mut submodule.include, &self.include);
+        uninherit(&mut submodule.exclude, &self.exclude);
+    }
+}
+
+fn inherit<T: Clone>(field: &mut Option<T>, template: &Option<T>) {
+    if field.is_none() {
+        field.clone_from(template);
+    }
+}
+
+fn uninherit<T: PartialEq>(field: &mut Option<T>, template: &Option<T>) {
+    if template.is_some() && field == template {
+        *field = None;
+    }
+}
+
+impl SubmoduleConfig {
+    /// Creates a submodule with the default Flutter-style include set.
+    pub fn new(name: &str) -> Self {
+        SubmoduleConfig {
+            name: name.to_string(),
+            path: name.to_string(),
+            target: None,
+            target_command: None,
+            target_subdir: None,
+            delete: None,
+            extra_rsync_args: None,
+            timeout_secs: None,
+            pre_sync: None,
+            post_sync: None,
+            validate_command: None,
+            cleanup_command: None,
+            extends: None,
+            include: Some(vec![
+                "lib/***".to_string(),
+                "pubspec.yaml".to_string(),
+                "test/***".to_string(),
+            ]),
+            exclude: Some(vec!["*".to_string()]),
+            meta: serde_json::Value::Null,
+        }
+    }
+
+    /// Creates a submodule that mirrors its whole source directory except
+    /// Git metadata.
+    pub fn mirror(name: &str) -> Self {
+        SubmoduleConfig {
+            include: None,
+            exclude: Some(vec![".git/".to_string()]),
+            ..SubmoduleConfig::new(name)
+        }
+    }
+
+    /// The include patterns, empty when unset.
+    pub fn include(&self) -> &[String] {
+        self.include.as_deref().unwrap_or_default()
+    }
+
+    /// The exclude patterns, empty when unset.
+    pub fn exclude(&self) -> &[String] {
+        self.exclude.as_deref().unwrap_or_default()
+    }
+
+    /// Appends `target_subdir`, if set, to a computed target directory.
+    pub fn in_subdir(&self, target: PathBuf) -> PathBuf {
+        match &self.target_subdir {
+            Some(subdir) => target.join(subdir),
+            None => target,
+        }
+    }
+
+    /// Returns whether `tag` is listed in this submodule's `meta.tags`.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.meta["tags"]
+            .as_array()
+            .is_some_and(|tags| tags.iter().any(|t| t.as_str() == Some(tag)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct AppConfig {
+    /// Schema version of the file; see `CONFIG_VERSION`.
+    #[serde(default)]
+    pub version: u32,
+    /// Submodules from `config.json` followed by those from `SUBMODULES_DIR`.
+    #[serde(default)]
+    pub submodules: Vec<SubmoduleConfig>,
+    /// Named templates referenced by `SubmoduleConfig::extends`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub templates: BTreeMap<String, SubmoduleTemplate>,
+    /// Keep submodules in their existing order on save instead of sorting
+    /// them by name.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keep_order: bool,
+    /// Which of the attributes bundled by rsync's `-a` every sync preserves.
+    #[serde(default, skip_serializing_if = "ArchiveFlags::is_default")]
+    pub archive_flags: ArchiveFlags,
+    /// Directory, relative to the monorepo root, that every submodule `path`
+    /// is resolved against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_prefix: Option<PathBuf>,
+    /// Include patterns applied to every submodule, after its own rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub global_include: Vec<String>,
+    /// Exclude patterns applied to every submodule, after its own rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub global_exclude: Vec<String>,
+    /// Exclude patterns added to the `--exclude-build-artifacts` preset.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub build_artifacts: Vec<String>,
+    /// Directory the default `<base>/<name>` targets are created in, instead
+    /// of the monorepo's parent. May start with `{home}`, `{xdg_data}` or
+    /// `{xdg_config}`; a relative path is resolved against the monorepo root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_base: Option<String>,
+}
+
+/// The individual flags rsync's `-a` (`-rlptgoD`) stands for, passed
+/// explicitly. Permissions, owner and group are off by default so targets
+/// stay owned by the user running the sync.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ArchiveFlags {
+    pub recursive: bool,
+    pub links: bool,
+    pub perms: bool,
+    pub times: bool,
+    pub group: bool,
+    pub owner: bool,
+    pub devices: bool,
+    pub specials: bool,
+}
+
+impl Default for ArchiveFlags {
+    fn default() -> Self {
+        ArchiveFlags {
+            recursive: true,
+            links: true,
+            perms: false,
+            times: true,
+            group: false,
+            owner: false,
+            devices: true,
+            specials: true,
+        }
+    }
+}
+
+impl ArchiveFlags {
+    fn is_default(&self) -> bool {
+        *self == ArchiveFlags::default()
+    }
+
+    /// The enabled flags as rsync arguments, in `-rlptgoD` order.
+    pub fn args(&self) -> Vec<&'static str> {
+        [
+            (self.recursive, "--recursive"),
+            (self.links, "--links"),
+            (self.perms, "--perms"),
+            (self.times, "--times"),
+            (self.group, "--group"),
+            (self.owner, "--owner"),
+            (self.devices, "--devices"),
+            (self.specials, "--specials"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, flag)| enabled.then_some(flag))
+        .collect()
+    }
+}
+
+impl AppConfig {
+    pub fn find(&self, name: &str) -> Option<&SubmoduleConfig> {
+        self.submodules.iter().find(|s| s.name == name)
+    }
+
+    /// The source directory of `submodule` in the monorepo at `root`.
+    pub fn source_path(&self, root: &Path, submodule: &SubmoduleConfig) -> PathBuf {
+        match &self.source_prefix {
+            Some(prefix) => root.join(prefix).join(&submodule.path),
+            None => root.join(&submodule.path),
+        }
+    }
+
+    /// The directory default targets are created in: the expanded
+    /// `target_base`, or the parent of the monorepo at `root`.
+    pub fn target_base(&self, root: &Path) -> io::Result<PathBuf> {
+        match &self.target_base {
+            Some(base) => Ok(root.join(expand_base_placeholders(base)?)),
+            None => root.parent().map(Path::to_path_buf).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Monorepo root has no parent directory to sync into",
+                )
+            }),
+        }
+    }
+
+    /// Resolves a template's effective settings by following its `extends`
+    /// chain. `chain` holds the templates visited so far, to detect cycles.
+    fn resolve_template(
+        &self,
+        name: &str,
+        chain: &mut Vec<String>,
+    ) -> io::Result<SubmoduleTemplate> {
+        if chain.iter().any(|visited| visited == name) {
+            chain.push(name.to_string());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Template inheritance cycle: {}", chain.join(" -> ")),
+            ));
+        }
+        let template = self.templates.get(name).ok_or_else(|| {
+            let message = match chain.last() {
+                Some(parent) => format!("Unknown template '{}' extended by '{}'", name, parent),
+                None => format!("Unknown template '{}'", name),
+            };
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })?;
+
+        chain.push(name.to_string());
+        let mut resolved = template.clone();
+        if let Some(parent) = &template.extends {
+            let parent = self.resolve_template(parent, chain)?;
+            resolved.inherit_from(&parent);
+        }
+        resolved.extends = None;
+        Ok(resolved)
+    }
+
+    /// Resolves the template of every submodule that extends one.
+    fn submodule_templates(&self) -> io::Result<Vec<Option<SubmoduleTemplate>>> {
+        self.submodules
+            .iter()
+            .map(|submodule| match &submodule.extends {
+                Some(name) => self
+                    .resolve_template(name, &mut Vec::new())
+                    .map(Some)
+                    .map_err(|e| {
+                        io::Error::new(e.kind(), format!("Submodule '{}': {}", submodule.name, e))
+                    }),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Fills each submodule's unset fields from its template.
+    fn apply_templates(&mut self) -> io::Result<()> {
+        let templates = self.submodule_templates()?;
+        for (submodule, template) in self.submodules.iter_mut().zip(templates) {
+            if let Some(template) = template {
+                template.apply_to(submodule);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a copy with fields that match the submodule's template cleared,
+    /// so saving keeps them inherited rather than writing them out.
+    fn without_inherited(&self) -> io::Result<AppConfig> {
+        let mut config = self.clone();
+        let templates = self.submodule_templates()?;
+        for (submodule, template) in config.submodules.iter_mut().zip(templates) {
+            if let Some(template) = template {
+                template.remove_from(submodule);
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Replaces the `{home}`, `{xdg_data}` and `{xdg_config}` placeholders in a
+/// `target_base` with `$HOME`, `$XDG_DATA_HOME` and `$XDG_CONFIG_HOME`. The
+/// XDG directories default to `~/.local/share` and `~/.config` when their
+/// variables are unset, as the XDG base directory spec prescribes.
+fn expand_base_placeholders(base: &str) -> io::Result<PathBuf> {
+    let unknown = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "target_base '{}' may only start with {{home}}, {{xdg_data}} or {{xdg_config}}",
+                base
+            ),
+        )
+    };
+    let (placeholder, rest) = match base.strip_prefix('{').and_then(|b| b.split_once('}')) {
+        Some(parts) => parts,
+        None if base.contains('{') => return Err(unknown()),
+        None => return Ok(PathBuf::from(base)),
+    };
+    let env_dir = |var: &str| {
+        std::env::var_os(var)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    };
+    let home = || {
+        env_dir("HOME").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "target_base '{}' needs the home directory, but HOME is not set",
+                    base
+                ),
+            )
+        })
+    };
+    let dir = match placeholder {
+        "home" => home()?,
+        "xdg_data" => match env_dir("XDG_DATA_HOME") {
+            Some(dir) => dir,
+            None => home()?.join(".local/share"),
+        },
+        "xdg_config" => match env_dir("XDG_CONFIG_HOME") {
+            Some(dir) => dir,
+            None => home()?.join(".config"),
+        },
+        _ => return Err(unknown()),
+    };
+    match rest.trim_start_matches('/') {
+        "" => Ok(dir),
+        rest => Ok(dir.join(rest)),
+    }
+}
+
+/// Commits `config.json`, and the per-submodule files if that layout is in
+/// use, into the Git repository containing `root` with `message`. Only the
+/// config is committed, whatever else is staged.
+/// Skipped with a note when Git is unavailable, `root` is not in a Git
+/// repository, or the file has no changes. `quiet` (from `--quiet`) drops
+/// the notes and the success message.
+pub fn commit_config(root: &Path, message: &str, quiet: bool) -> io::Result<()> {
+    ensure_writable()?;
+    let config_path = Path::new(CONFIG_DIR).join(CONFIG_FILE);
+    let mut paths = vec![config_path.as_os_str()];
+    let submodules_path = Path::new(CONFIG_DIR).join(SUBMODULES_DIR);
+    if root.join(&submodules_path).is_dir() {
+        paths.push(submodules_path.as_os_str());
+    }
+    let git = |args: &[&OsStr]| {
+        ProcessCommand::new("git")
+            .arg("-C")
+            .arg(root)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+    };
+    match git(&["rev-parse".as_ref(), "--git-dir".as_ref()]) {
+        Ok(status) if status.success() => {}
+        Ok(_) => {
+            if !quiet {
+                println!("Not a Git repository; config not committed.");
+            }
+            return Ok(());
+        }
+        Err(_) => {
+            if !quiet {
+                println!("Git is not available; config not committed.");
+            }
+            return Ok(());
+        }
+    }
+
+    // `--all` also stages submodule files deleted by `remove` or `rename`.
+    let add: Vec<&OsStr> = ["add".as_ref(), "--all".as_ref(), "--".as_ref()]
+        .into_iter()
+        .chain(paths.iter().copied())
+        .collect();
+    if !git(&add)?.success() {
+        return Err(io::Error::other(format!(
+            "git add {} failed",
+            config_path.display()
+        )));
+    }
+    let diff: Vec<&OsStr> = [
+        "diff".as_ref(),
+        "--cached".as_ref(),
+        "--quiet".as_ref(),
+        "--".as_ref(),
+    ]
+    .into_iter()
+    .chain(paths.iter().copied())
+    .collect();
+    if git(&diff)?.success() {
+        if !quiet {
+            println!("Config unchanged; nothing to commit.");
+        }
+        return Ok(());
+    }
+    let status = ProcessCommand::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["commit", "--quiet", "-m", message, "--"])
+        .args(&paths)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "git commit of {} failed",
+            config_path.display()
+        )));
+    }
+    if !quiet {
+        println!("Committed {} to Git.", config_path.display());
+    }
+    Ok(())
+}
+
+/// Name of the optional rules file at a submodule's source root, letting
+/// submodule owners keep sync rules next to their code.
+pub const SOURCE_RULES_FILE: &str = ".monorepo-sync";
+
+/// Include/exclude rules read from a submodule's `.monorepo-sync` file. They
+/// are evaluated before the rules in `config.json`, so they win wherever both
+/// match a path; with `replace` the config's rules are ignored entirely.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SourceRules {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub replace: bool,
+}
+
+/// Reads the `.monorepo-sync` file in `source`, if there is one.
+pub fn load_source_rules(source: &Path) -> io::Result<Option<SourceRules>> {
+    let rules_path = source.join(SOURCE_RULES_FILE);
+    if !rules_path.is_file() {
+        return Ok(None);
+    }
+    let file = fs::File::open(&rules_path)?;
+    serde_json::from_reader(BufReader::new(file))
+        .map(Some)
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse {}: {}", rules_path.display(), e),
+            )
+        })
+}
+
+/// Checks that a submodule name can be used as a directory name on common
+/// filesystems, since it doubles as the default source and target name.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("name is empty".to_string());
+    }
+    if name == "." || name == ".." {
+        return Err(format!("'{}' is not a valid directory name", name));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| c.is_control() || "/\\:*?\"<>|".contains(*c))
+    {
+        return Err(format!("'{}' contains the unsafe character {:?}", name, c));
+    }
+    Ok(())
+}
+
+/// Checks that a `target_subdir` stays inside the target: it must be a
+/// non-empty relative path without `..` components.
+pub fn validate_subdir(subdir: &Path) -> Result<(), String> {
+    let mut components = subdir.components().peekable();
+    if components.peek().is_none() {
+        return Err("target_subdir is empty".to_string());
+    }
+    if components.any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!(
+            "target_subdir {:?} must be a relative path inside the target",
+            subdir
+        ));
+    }
+    Ok(())
+}
+
+/// Finds the monorepo containing the current directory by walking up to the
+/// nearest ancestor with a `.monorepo` directory, like Git does for `.git`.
+pub fn find_root() -> io::Result<PathBuf> {
+    let current_dir = std::env::current_dir()?;
+    match current_dir
+        .ancestors()
+        .find(|dir| dir.join(CONFIG_DIR).is_dir())
+    {
+        Some(root) => Ok(root.to_path_buf()),
+        None => Err(not_initialized()),
+    }
+}
+
+fn not_initialized() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        "Monorepo not initialized. Run 'monorepo-agent init <submodules>' first.",
+    )
+}
+
+/// Returns the config directory of the monorepo rooted at `root`, failing if
+/// the monorepo has not been initialized.
+pub fn require_config_dir(root: &Path) -> io::Result<PathBuf> {
+    let config_dir = root.join(CONFIG_DIR);
+    if !config_dir.exists() {
+        return Err(not_initialized());
+    }
+    check_config_dir(&config_dir)?;
+    Ok(config_dir)
+}
+
+/// Fails with a clear message if `config_dir` exists but is not a directory.
+pub fn check_config_dir(config_dir: &Path) -> io::Result<()> {
+    if config_dir.exists() && !config_dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} exists but is not a directory; remove or rename it",
+                config_dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Loads the config from `config_dir`, returning an empty config if the file
+/// does not exist yet; the empty config is only saved by a command that
+/// changes it. Submodules defined in `SUBMODULES_DIR` are added after those
+/// in `config.json`. A config from an older schema version is migrated and
+/// rewritten (in memory only under `--config-readonly`); one from a newer
+/// version is refused.
+pub fn load_or_create_config(config_dir: &Path) -> io::Result<AppConfig> {
+    let config_path = config_dir.join(CONFIG_FILE);
+    let exists = config_path.exists();
+    if config_path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} exists but is a directory; expected a file",
+                config_path.display()
+            ),
+        ));
+    }
+
+    let mut config: AppConfig = if exists {
+        read_json(&config_path)?
+    } else {
+        AppConfig::default()
+    };
+    load_submodule_files(config_dir, &mut config)?;
+    if config.version > CONFIG_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is version {}, but this binary only understands up to version {}; \
+                 please upgrade monorepo-agent",
+                config_path.display(),
+                config.version,
+                CONFIG_VERSION
+            ),
+        ));
+    }
+    if exists && config.version < CONFIG_VERSION {
+        migrate(&mut config);
+        // Saved before templates are applied, so inherited fields stay
+        // inherited.
+        if !READONLY.load(Ordering::Relaxed) {
+            save_config(config_dir, &config)?;
+        }
+    }
+    config.apply_templates()?;
+    Ok(config)
+}
+
+/// Parses the JSON file at `path`, naming the file in parse errors.
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let file = fs::File::open(path)?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to parse {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Writes `value` to `path` as pretty-printed JSON with a trailing newline.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+/// Returns the `.json` files in `SUBMODULES_DIR`, sorted by name, or `None`
+/// when the per-submodule layout is not in use.
+pub fn submodule_files(config_dir: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+    let submodules_dir = config_dir.join(SUBMODULES_DIR);
+    if !submodules_dir.is_dir() {
+        return Ok(None);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&submodules_dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("json")) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(Some(files))
+}
+
+/// Appends the submodule defined in each `SUBMODULES_DIR` file to `config`.
+/// A file must be named after the submodule it defines, and a submodule may
+/// not also be listed in `config.json`.
+fn load_submodule_files(config_dir: &Path, config: &mut AppConfig) -> io::Result<()> {
+    for path in submodule_files(config_dir)?.unwrap_or_default() {
+        let submodule: SubmoduleConfig = read_json(&path)?;
+        if path.file_stem() != Some(OsStr::new(&submodule.name)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} defines submodule '{}'; rename the file to {}.json",
+                    path.display(),
+                    submodule.name,
+                    submodule.name
+                ),
+            ));
+        }
+        if config.find(&submodule.name).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Submodule '{}' is defined in both {} and {}",
+                    submodule.name,
+                    CONFIG_FILE,
+                    path.display()
+                ),
+            ));
+        }
+        config.submodules.push(submodule);
+    }
+    Ok(())
+}
+
+/// Template that `init --init-from` makes new submodules extend, when the
+/// seed config defines it.
+pub const DEFAULT_TEMPLATE: &str = "default";
+
+/// Reads a config file to seed a new monorepo's config from, refusing one
+/// that does not parse, is from a newer schema version or has broken
+/// templates.
+pub fn load_seed_config(path: &Path) -> io::Result<AppConfig> {
+    let mut config: AppConfig = read_json(path)?;
+    if config.version > CONFIG_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is version {}, but this binary only understands up to version {}",
+                path.display(),
+                config.version,
+                CONFIG_VERSION
+            ),
+        ));
+    }
+    migrate(&mut config);
+    config.apply_templates()?;
+    Ok(config)
+}
+
+/// Brings a config from an older schema version up to `CONFIG_VERSION`.
+/// Version 0 only lacks fields added since, which deserialize to their
+/// defaults, so nothing else changes yet.
+fn migrate(config: &mut AppConfig) {
+    config.version = CONFIG_VERSION;
+}
+
+/// Writes the config to `config_dir` as pretty-printed JSON. Submodules are
+/// sorted by name unless `keep_order` is set, so edits by different people
+/// produce minimal diffs. When `SUBMODULES_DIR` exists, each submodule is
+/// written to its own file there instead, and files of submodules no longer
+/// in the config are removed.
+pub fn save_config(config_dir: &Path, config: &AppConfig) -> io::Result<()> {
+    ensure_writable()?;
+    let config_path = config_dir.join(CONFIG_FILE);
+    let mut config = config.without_inherited()?;
+    config.version = CONFIG_VERSION;
+    if !config.keep_order {
+        config.submodules.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    if let Some(files) = submodule_files(config_dir)? {
+        let submodules = std::mem::take(&mut config.submodules);
+        let paths = submodule_paths(config_dir, &submodules)?;
+        for (submodule, path) in submodules.iter().zip(&paths) {
+            write_json(path, submodule)?;
+        }
+        remove_stale_files(&files, &paths)?;
+    }
+    write_json(&config_path, &config)
+}
+
+/// The `SUBMODULES_DIR` file of each submodule, refusing names that would
+/// not stay inside the folder.
+fn submodule_paths(config_dir: &Path, submodules: &[SubmoduleConfig]) -> io::Result<Vec<PathBuf>> {
+    submodules
+        .iter()
+        .map(|submodule| {
+            validate_name(&submodule.name).map_err(|reason| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid submodule name '{}': {}", submodule.name, reason),
+                )
+            })?;
+            Ok(config_dir
+                .join(SUBMODULES_DIR)
+                .join(format!("{}.json", submodule.name)))
+        })
+        .collect()
+}
+
+/// Removes the submodule files found on load that were not written back.
+fn remove_stale_files(files: &[PathBuf], written: &[PathBuf]) -> io::Result<()> {
+    for file in files.iter().filter(|file| !written.contains(file)) {
+        fs::remove_file(file)?;
+    }
+    Ok(())
+}
+
+/// The optional `SubmoduleConfig` fields in declaration order, which
+/// `upgrade_config` writes out explicitly. Keep in sync with the struct.
+const OPTIONAL_SUBMODULE_FIELDS: [&str; 14] = [
+    "target",
+    "target_command",
+    "target_subdir",
+    "delete",
+    "extra_rsync_args",
+    "timeout_secs",
+    "pre_sync",
+    "post_sync",
+    "validate_command",
+    "cleanup_command",
+    "extends",
+    "include",
+    "exclude",
+    "meta",
+];
+
+/// A JSON object whose entries serialize in the order given, unlike
+/// `serde_json::Map`, which sorts them.
+struct OrderedObject(Vec<(String, serde_json::Value)>);
+
+impl Serialize for OrderedObject {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// The config as `upgrade_config` writes it: the submodules first, then the
+/// remaining settings.
+struct UpgradedConfig {
+    submodules: Vec<OrderedObject>,
+    settings: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Serialize for UpgradedConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.settings.len() + 1))?;
+        map.serialize_entry("submodules", &self.submodules)?;
+        for (key, value) in &self.settings {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Rewrites `config.json` with every optional submodule field written out,
+/// missing ones set to their defaults, so the file shows the current schema.
+/// Returns the fields added to each submodule that lacked any. Commands that
+/// save the config later write the compact form again. Submodules kept in
+/// `SUBMODULES_DIR` are upgraded in their own files.
+pub fn upgrade_config(config_dir: &Path) -> io::Result<Vec<(String, Vec<&'static str>)>> {
+    ensure_writable()?;
+    let config_path = config_dir.join(CONFIG_FILE);
+    let config = load_or_create_config(config_dir)?;
+    let read_raw = |path: &Path| -> io::Result<serde_json::Value> {
+        match fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(serde_json::Value::Null),
+            Err(e) => Err(e),
+        }
+    };
+    let raw = read_raw(&config_path)?;
+    let files = submodule_files(config_dir)?;
+
+    let mut config = config.without_inherited()?;
+    if !config.keep_order {
+        config.submodules.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    let paths = match files {
+        Some(_) => Some(submodule_paths(config_dir, &config.submodules)?),
+        None => None,
+    };
+    let mut added = Vec::new();
+    let mut submodules = Vec::new();
+    for (i, submodule) in config.submodules.iter().enumerate() {
+        let raw_file = match &paths {
+            Some(paths) => read_raw(&paths[i])?,
+            None => serde_json::Value::Null,
+        };
+        // A submodule still listed in config.json moves to its own file.
+        let on_disk = raw_file.as_object().map(|_| &raw_file).or_else(|| {
+            raw["submodules"]
+                .as_array()
+                .and_then(|list| list.iter().find(|s| s["name"] == submodule.name.as_str()))
+        });
+        let mut value = serde_json::to_value(submodule)?;
+        let mut entries = vec![
+            ("name".to_string(), value["name"].take()),
+            ("path".to_string(), value["path"].take()),
+        ];
+        let mut missing = Vec::new();
+        for field in OPTIONAL_SUBMODULE_FIELDS {
+            if on_disk.and_then(|s| s.get(field)).is_none() {
+                missing.push(field);
+            }
+            let value = value
+                .get_mut(field)
+                .map(serde_json::Value::take)
+                .unwrap_or_default();
+            entries.push((field.to_string(), value));
+        }
+        if !missing.is_empty() {
+            added.push((submodule.name.clone(), missing));
+        }
+        submodules.push(OrderedObject(entries));
+    }
+
+    let mut settings = match serde_json::to_value(&config)? {
+        serde_json::Value::Object(settings) => settings,
+        _ => serde_json::Map::new(),
+    };
+    settings.remove("submodules");
+    if let (Some(files), Some(paths)) = (files, paths) {
+        for (submodule, path) in submodules.drain(..).zip(&paths) {
+            write_json(path, &submodule)?;
+        }
+        remove_stale_files(&files, &paths)?;
+    }
+    let upgraded = UpgradedConfig {
+        submodules,
+        settings,
+    };
+    write_json(&config_path, &upgraded)?;
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templated(json: &str) -> AppConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn templates_inherit_through_extends_and_submodule_fields_win() {
+        let mut config = templated(
+            r#"{
+                "templates": {
+                    "base": {"include": ["lib/***"], "exclude": ["*"]},
+                    "web": {"extends": "base", "include": ["dist/***"]}
+                },
+                "submodules": [
+                    {"name": "app", "path": "app", "extends": "web"},
+                    {"name": "api", "path": "api", "extends": "web", "exclude": ["*.log"]}
+                ]
+            }"#,
+        );
+        config.apply_templates().unwrap();
+        assert_eq!(config.submodules[0].include(), ["dist/***"]);
+        assert_eq!(config.submodules[0].exclude(), ["*"]);
+        assert_eq!(config.submodules[1].include(), ["dist/***"]);
+        assert_eq!(config.submodules[1].exclude(), ["*.log"]);
+    }
+
+    #[test]
+    fn templates_fill_only_unset_fields() {
+        let mut config = templated(
+            r#"{
+                "templates": {
+                    "base": {
+                        "include": ["lib/***"],
+                        "exclude": ["*"],
+                        "delete": false,
+                        "target_subdir": "www",
+                        "extra_rsync_args": ["--checksum"]
+                    }
+                },
+                "submodules": [
+                    {"name": "app", "path": "app", "extends": "base"},
+                    {
+                        "name": "api",
+                        "path": "api",
+                        "extends": "base",
+                        "exclude": [],
+                        "delete": true,
+                        "extra_rsync_args": [],
+                        "include": null
+                    }
+                ]
+            }"#,
+        );
+        config.apply_templates().unwrap();
+        let (app, api) = (&config.submodules[0], &config.submodules[1]);
+        assert_eq!(app.delete, Some(false));
+        assert_eq!(app.target_subdir, Some(PathBuf::from("www")));
+        assert_eq!(app.extra_rsync_args, Some(vec!["--checksum".to_string()]));
+        // An explicit empty list overrides the template; `null` inherits.
+        assert_eq!(api.include(), ["lib/***"]);
+        assert_eq!(api.exclude, Some(Vec::new()));
+        assert_eq!(api.extra_rsync_args, Some(Vec::new()));
+        assert_eq!(api.delete, Some(true));
+        assert_eq!(api.target_subdir, Some(PathBuf::from("www")));
+
+        let saved = serde_json::to_value(config.without_inherited().unwrap()).unwrap();
+        assert_eq!(
+            saved["submodules"][0],
+            serde_json::json!({"name": "app", "path": "app", "extends": "base"})
+        );
+        assert_eq!(
+            saved["submodules"][1],
+            serde_json::json!({
+                "name": "api",
+                "path": "api",
+                "extends": "base",
+                "delete": true,
+                "extra_rsync_args": [],
+                "exclude": []
+            })
+        );
+    }
+
+    #[test]
+    fn template_cycles_and_unknown_templates_are_errors() {
+        let mut self_reference = templated(
+            r#"{"templates": {"a": {"extends": "a"}},
+                "submodules": [{"name": "app", "path": "app", "extends": "a"}]}"#,
+        );
+        let error = self_reference.apply_templates().unwrap_err();
+        assert!(error.to_string().contains("cycle: a -> a"), "{}", error);
+
+        let mut cycle = templated(
+            r#"{"templates": {"a": {"extends": "b"}, "b": {"extends": "a"}},
+                "submodules": [{"name": "app", "path": "app", "extends": "a"}]}"#,
+        );
+        let error = cycle.apply_templates().unwrap_err();
+        assert!(
+            error.to_string().contains("cycle: a -> b -> a"),
+            "{}",
+            error
+        );
+
+        let mut missing = templated(
+            r#"{"templates": {"a": {"extends": "gone"}},
+                "submodules": [{"name": "app", "path": "app", "extends": "a"}]}"#,
+        );
+        let error = missing.apply_templates().unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Unknown template 'gone' extended by 'a'"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn optional_fields_list_every_submodule_field() {
+        // A struct literal without `..`, so a new field fails to compile here
+        // until it is populated and then shows up in the comparison.
+        let submodule = SubmoduleConfig {
+            name: "app".to_string(),
+            path: "app".to_string(),
+            target: Some(PathBuf::from("deploy")),
+            target_command: Some("echo deploy".to_string()),
+            target_subdir: Some(PathBuf::from("www")),
+            delete: Some(false),
+            extra_rsync_args: Some(vec!["--checksum".to_string()]),
+            timeout_secs: Some(30),
+            pre_sync: Some("true".to_string()),
+            post_sync: Some("true".to_string()),
+            validate_command: Some("true".to_string()),
+            cleanup_command: Some("true".to_string()),
+            extends: Some(DEFAULT_TEMPLATE.to_string()),
+            include: Some(vec!["lib/***".to_string()]),
+            exclude: Some(vec!["*".to_string()]),
+            meta: serde_json::json!({"owner": "team"}),
+        };
+        let value = serde_json::to_value(&submodule).unwrap();
+        let mut keys: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !matches!(*key, "name" | "path"))
+            .collect();
+        keys.sort_unstable();
+        let mut expected = OPTIONAL_SUBMODULE_FIELDS.to_vec();
+        expected.sort_unstable();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn loads_and_upgrades_version_0_config() {
+        let config_dir =
+            std::env::temp_dir().join(format!("monorepo-agent-config-{}", std::process::id()));
+        fs::create_dir_all(&config_dir).unwrap();
+        let config_path = config_dir.join(CONFIG_FILE);
+        fs::write(
+            &config_path,
+            r#"{"submodules": [{"name": "app", "path": "app", "include": ["lib/***"]}]}"#,
+        )
+        .unwrap();
+
+        let config = load_or_create_config(&config_dir).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.submodules.len(), 1);
+        assert_eq!(config.submodules[0].name, "app");
+        assert_eq!(config.submodules[0].include(), ["lib/***"]);
+        assert!(config.submodules[0].exclude().is_empty());
+
+        let rewritten: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+        assert_eq!(rewritten["version"], CONFIG_VERSION);
+        assert_eq!(rewritten["submodules"][0]["name"], "app");
+
+        let reloaded = load_or_create_config(&config_dir).unwrap();
+        assert_eq!(reloaded.version, CONFIG_VERSION);
+        assert_eq!(reloaded.submodules[0].include, config.submodules[0].include);
+        fs::remove_dir_all(&config_dir).unwrap();
+    }
+
+    #[test]
+    fn loads_and_saves_per_submodule_files() {
+        let config_dir = std::env::temp_dir().join(format!(
+            "monorepo-agent-submodule-files-{}",
+            std::process::id()
+        ));
+        let submodules_dir = config_dir.join(SUBMODULES_DIR);
+        fs::create_dir_all(&submodules_dir).unwrap();
+        fs::write(
+            config_dir.join(CONFIG_FILE),
+            r#"{"version": 1, "submodules": [{"name": "app", "path": "app"}]}"#,
+        )
+        .unwrap();
+        fs::write(
+            submodules_dir.join("web.json"),
+            r#"{"name": "web", "path": "web", "include": ["dist/***"]}"#,
+        )
+        .unwrap();
+        fs::write(
+            submodules_dir.join("old.json"),
+            r#"{"name": "old", "path": "old"}"#,
+        )
+        .unwrap();
+
+        let mut config = load_or_create_config(&config_dir).unwrap();
+        let names: Vec<&str> = config.submodules.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["app", "old", "web"]);
+        assert_eq!(config.find("web").unwrap().include(), ["dist/***"]);
+
+        config.submodules.retain(|s| s.name != "old");
+        save_config(&config_dir, &config).unwrap();
+        assert!(submodules_dir.join("app.json").is_file());
+        assert!(submodules_dir.join("web.json").is_file());
+        assert!(!submodules_dir.join("old.json").exists());
+        let saved: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(config_dir.join(CONFIG_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(saved["submodules"], serde_json::json!([]));
+
+        let reloaded = load_or_create_config(&config_dir).unwrap();
+        assert_eq!(reloaded.submodules.len(), 2);
+        fs::remove_dir_all(&config_dir).unwrap();
+    }
+}
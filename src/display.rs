@@ -0,0 +1,76 @@
+use crate::rsync;
+use crate::sync::normalize_lexically;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Set by `--relative-paths`: messages show local paths relative to this
+/// directory instead of absolute.
+static RELATIVE_BASE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Makes every path shown through [`path`] relative to `base` for the rest of
+/// the process.
+pub fn set_relative_base(base: PathBuf) {
+    let _ = RELATIVE_BASE.set(base);
+}
+
+/// Wraps `path` for a `{:?}` message, relative to the base set by
+/// `--relative-paths` if there is one. Remote paths are shown unchanged.
+pub fn path(path: &Path) -> Shown<'_> {
+    Shown(path)
+}
+
+pub struct Shown<'a>(&'a Path);
+
+impl fmt::Debug for Shown<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match RELATIVE_BASE.get() {
+            Some(base) if self.0.is_absolute() && !rsync::is_remote(self.0) => {
+                relative_to(self.0, base).fmt(f)
+            }
+            _ => self.0.fmt(f),
+        }
+    }
+}
+
+/// Spells `path` relative to `base`, climbing out with `..` where the two
+/// diverge. Both are normalized lexically first.
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let path = normalize_lexically(path);
+    let base = normalize_lexically(base);
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    while let (Some(a), Some(b)) = (path_components.peek(), base_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        base_components.next();
+    }
+    let mut relative: PathBuf = base_components.map(|_| Component::ParentDir).collect();
+    relative.extend(path_components);
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    relative
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_to_climbs_out_of_the_base() {
+        let base = Path::new("/repo");
+        assert_eq!(relative_to(Path::new("/repo/a/b"), base), Path::new("a/b"));
+        assert_eq!(relative_to(Path::new("/repo"), base), Path::new("."));
+        assert_eq!(
+            relative_to(Path::new("/deploy/./app"), base),
+            Path::new("../deploy/app")
+        );
+        assert_eq!(
+            relative_to(Path::new("/repo/x/../y"), Path::new("/repo/z/")),
+            Path::new("../y")
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config::{self, AppConfig};
+use crate::display;
+use crate::prompt;
+use crate::rsync;
+use crate::sync;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Cleans up a filter pattern: trims whitespace, drops a leading `./` and
+/// collapses repeated slashes. Returns `None` for a pattern that is empty.
+fn normalize_pattern(pattern: &str) -> Option<String> {
+    let trimmed = pattern.trim();
+    let trimmed = trimmed.strip_prefix("./").unwrap_or(trimmed);
+    let mut normalized = String::with_capacity(trimmed.len());
+    for c in trimmed.chars() {
+        if c == '/' && normalized.ends_with('/') {
+            continue;
+        }
+        normalized.push(c);
+    }
+    (!normalized.is_empty()).then_some(normalized)
+}
+
+/// Normalizes every pattern in `patterns` and drops empty and duplicate
+/// ones, recording each change as a message.
+fn normalize_patterns(patterns: &mut Vec<String>, owner: &str, changes: &mut Vec<String>) {
+    let mut normalized = Vec::with_capacity(patterns.len());
+    for pattern in patterns.iter() {
+        match normalize_pattern(pattern) {
+            Some(clean) if normalized.contains(&clean) => {
+                changes.push(format!("{}: duplicate pattern '{}'", owner, pattern));
+            }
+            Some(clean) => {
+                if &clean != pattern {
+                    changes.push(format!("{}: pattern '{}' -> '{}'", owner, pattern, clean));
+                }
+                normalized.push(clean);
+            }
+            None => changes.push(format!("{}: empty pattern '{}'", owner, pattern)),
+        }
+    }
+    *patterns = normalized;
+}
+
+/// Normalizes the patterns of every submodule and template in `app_config`,
+/// returning a description of each change made.
+fn normalize_config(app_config: &mut AppConfig) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (name, template) in &mut app_config.templates {
+        let owner = format!("template '{}'", name);
+        for patterns in [&mut template.include, &mut template.exclude]
+            .into_iter()
+            .flatten()
+        {
+            normalize_patterns(patterns, &owner, &mut changes);
+        }
+    }
+    for submodule in &mut app_config.submodules {
+        let owner = format!("'{}'", submodule.name);
+        for patterns in [&mut submodule.include, &mut submodule.exclude]
+            .into_iter()
+            .flatten()
+        {
+            normalize_patterns(patterns, &owner, &mut changes);
+        }
+    }
+    changes
+}
+
+/// Diagnoses common misconfigurations and, with `fix`, repairs them: missing
+/// target directories are created, malformed patterns are normalized and,
+/// after confirmation, submodules whose source is missing are removed.
+pub fn doctor(fix: bool, yes: bool) -> io::Result<()> {
+    if fix {
+        config::ensure_writable()?;
+    }
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let mut app_config = config::load_or_create_config(&config_dir)?;
+
+    let mut missing_sources = Vec::new();
+    let mut missing_targets: Vec<PathBuf> = Vec::new();
+    for plan in sync::plan_submodules(&root, &app_config, None)? {
+        if !plan.source_path.is_dir() {
+            missing_sources.push((plan.submodule.name.clone(), plan.source_path));
+        } else if !plan.target_unresolved
+            && !rsync::is_remote(&plan.target_path)
+            && !plan.target_path.exists()
+        {
+            missing_targets.push(plan.target_path);
+        }
+    }
+    let mut normalized = app_config.clone();
+    let pattern_changes = normalize_config(&mut normalized);
+
+    let issues = missing_sources.len() + missing_targets.len() + pattern_changes.len();
+    if issues == 0 {
+        println!("No problems found.");
+        return Ok(());
+    }
+    for (name, source) in &missing_sources {
+        println!(
+            "'{}': source {:?} does not exist",
+            name,
+            display::path(source)
+        );
+    }
+    for target in &missing_targets {
+        println!("target {:?} does not exist", display::path(target));
+    }
+    for change in &pattern_changes {
+        println!("{}", change);
+    }
+    if !fix {
+        println!(
+            "{} problem(s) found; run 'monorepo-agent doctor --fix' to repair them.",
+            issues
+        );
+        return Ok(());
+    }
+
+    for target in &missing_targets {
+        fs::create_dir_all(target)?;
+        println!("Created {:?}", display::path(target));
+    }
+    if !pattern_changes.is_empty() {
+        app_config = normalized;
+        config::save_config(&config_dir, &app_config)?;
+        println!("Normalized {} pattern problem(s).", pattern_changes.len());
+    }
+    if !missing_sources.is_empty() {
+        let question = format!(
+            "Remove {} submodule(s) with missing sources from the config?",
+            missing_sources.len()
+        );
+        if prompt::confirm(&question, yes)? {
+            app_config
+                .submodules
+                .retain(|s| !missing_sources.iter().any(|(name, _)| name == &s.name));
+            config::save_config(&config_dir, &app_config)?;
+            for (name, _) in &missing_sources {
+                println!("Removed '{}' from the config.", name);
+            }
+        } else {
+            println!("Keeping submodules with missing sources.");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use crate::config;
+use crate::sync::SyncSummary;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Name of the run history inside the config directory: one JSON object per
+/// line, appended by every sync that is not a dry run and recorded at least
+/// one submodule, whether it synced, was skipped or failed.
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// One line of the history file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HistoryEntry {
+    /// RFC 3339 start time of the run.
+    pub timestamp: String,
+    pub submodules: Vec<HistorySubmodule>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HistorySubmodule {
+    pub name: String,
+    pub outcome: String,
+    pub changes: usize,
+}
+
+/// Appends a record of the run summarized by `summary`, started at
+/// `timestamp`, to the history file in `config_dir`.
+pub fn append(config_dir: &Path, timestamp: &str, summary: &SyncSummary) -> io::Result<()> {
+    let entry = HistoryEntry {
+        timestamp: timestamp.to_string(),
+        submodules: summary
+            .results
+            .iter()
+            .map(|result| HistorySubmodule {
+                name: result.name.clone(),
+                outcome: result.outcome.as_str().to_string(),
+                changes: result.changes,
+            })
+            .collect(),
+    };
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config_dir.join(HISTORY_FILE))?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)
+}
+
+/// Reads every run in the history file of `config_dir`, oldest first. Lines
+/// that do not parse, such as one cut short by a crash, are skipped with a
+/// warning.
+pub fn load(config_dir: &Path) -> io::Result<Vec<HistoryEntry>> {
+    let path = config_dir.join(HISTORY_FILE);
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => eprintln!(
+                "Warning: skipping line {} of {}: {}",
+                number + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok(entries)
+}
+
+/// Prints the `limit` most recent runs, newest first, with each submodule's
+/// outcome.
+pub fn history(limit: usize) -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let entries = load(&config_dir)?;
+    if entries.is_empty() {
+        println!("No sync runs recorded yet.");
+        return Ok(());
+    }
+
+    for entry in entries.iter().rev().take(limit) {
+        let failed = entry
+            .submodules
+            .iter()
+            .filter(|s| s.outcome != "synced")
+            .count();
+        println!(
+            "{}  {} submodule(s), {} not synced",
+            entry.timestamp,
+            entry.submodules.len(),
+            failed
+        );
+        let width = entry
+            .submodules
+            .iter()
+            .map(|s| s.name.len())
+            .max()
+            .unwrap_or(0);
+        for submodule in &entry.submodules {
+            println!(
+                "  {:width$}  {} ({} change(s))",
+                submodule.name,
+                submodule.outcome,
+                submodule.changes,
+                width = width
+            );
+        }
+    }
+    Ok(())
+}
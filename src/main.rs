@@ -0,0 +1,1216 @@
+mod audit;
+mod bench;
+mod config;
+mod display;
+mod doctor;
+mod history;
+mod pattern;
+mod prompt;
+mod restore;
+mod rsync;
+mod state;
+mod sync;
+mod update;
+mod verify;
+mod watch;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use config::SubmoduleConfig;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use sync::{SyncOptions, SyncOutcome, SyncSummary, Verbosity};
+use watch::InitialSync;
+
+#[derive(Parser)]
+#[command(name = "monorepo-agent")]
+#[command(version, about = "A tool for managing monorepos with Git and rsync", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+    /// Refuse to write the config: commands that would change it fail
+    /// instead, for configs that are managed externally
+    #[arg(long, global = true)]
+    config_readonly: bool,
+    /// Also print the exact rsync commands and rsync's own output
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+    /// Print only errors and warnings
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Show paths in messages relative to the monorepo root (or the current
+    /// directory outside a monorepo) instead of absolute
+    #[arg(long, global = true)]
+    relative_paths: bool,
+}
+
+impl Cli {
+    fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Initialize the monorepo with comma-separated submodules
+    Init {
+        /// Comma-separated submodule names, e.g. "user_app,business_app"
+        #[arg(required_unless_present = "from_gitmodules")]
+        submodules: Option<String>,
+        /// Also add a submodule for every path listed in .gitmodules
+        #[arg(long)]
+        from_gitmodules: bool,
+        /// Keep submodules in insertion order instead of sorting them by name on save
+        #[arg(long)]
+        keep_order: bool,
+        /// Sync each new submodule's whole directory (except .git) instead
+        /// of only lib/, pubspec.yaml and test/
+        #[arg(long, alias = "no-default-excludes")]
+        all_files: bool,
+        /// Start the new config from this template config file. New
+        /// submodules extend its "default" template, if it has one
+        #[arg(long, alias = "init-from-template-file", value_name = "FILE")]
+        init_from: Option<PathBuf>,
+        /// Commit the updated config.json to Git with this message
+        #[arg(long, value_name = "MESSAGE")]
+        commit_config: Option<String>,
+    },
+    /// Remove comma-separated submodules from the config, leaving their
+    /// target directories on disk
+    Remove {
+        /// Comma-separated submodule names, e.g. "user_app,business_app"
+        submodules: String,
+        /// Commit the updated config.json to Git with this message
+        #[arg(long, value_name = "MESSAGE")]
+        commit_config: Option<String>,
+    },
+    /// Rename a submodule, keeping all of its settings
+    Rename {
+        /// Current submodule name
+        old: String,
+        /// New submodule name
+        new: String,
+        /// Also rename the default target directory <base>/<old> to <base>/<new>
+        #[arg(long)]
+        move_target: bool,
+        /// Commit the updated config.json to Git with this message
+        #[arg(long, value_name = "MESSAGE")]
+        commit_config: Option<String>,
+    },
+    /// Replace a submodule's include and/or exclude patterns
+    SetPatterns {
+        /// Submodule to update
+        submodule: String,
+        /// Comma-separated include patterns, e.g. "lib/***,pubspec.yaml";
+        /// an empty string clears them
+        #[arg(long, value_name = "PATTERNS")]
+        include: Option<String>,
+        /// Comma-separated exclude patterns; an empty string clears them
+        #[arg(long, value_name = "PATTERNS")]
+        exclude: Option<String>,
+        /// Commit the updated config.json to Git with this message
+        #[arg(long, value_name = "MESSAGE")]
+        commit_config: Option<String>,
+    },
+    /// Sync submodules to their sibling directories
+    Sync {
+        /// Comma-separated submodule names to sync (defaults to all)
+        #[arg(short, long)]
+        submodules: Option<String>,
+        #[command(flatten)]
+        options: Box<SyncOptions>,
+        /// Fail when no submodules are configured or selected
+        #[arg(long)]
+        fail_on_empty: bool,
+        /// Exit code to use when the sync changed at least one target (never
+        /// for a dry run, which changes nothing)
+        #[arg(long, default_value_t = 0)]
+        exit_code_on_changes: i32,
+        /// Exit code to use when any submodule failed to sync
+        #[arg(long, default_value_t = 1)]
+        exit_code_on_failure: i32,
+        /// With --dry-run, exit with 2 if any target has drifted from its
+        /// source and 0 if all are in sync
+        #[arg(long, requires = "dry_run")]
+        detect_changes: bool,
+    },
+    /// Keep syncing submodules as their sources change, polling for changes
+    Watch {
+        /// Comma-separated submodule names to watch (defaults to all)
+        #[arg(short, long)]
+        submodules: Option<String>,
+        #[command(flatten)]
+        options: Box<SyncOptions>,
+        /// Seconds between polls of the sources
+        #[arg(long, value_name = "SECONDS", default_value_t = 2,
+              value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// Whether to sync everything on startup or wait for the first change
+        #[arg(long, value_enum, default_value_t = InitialSync::Full)]
+        watch_initial_sync: InitialSync,
+    },
+    /// Check that each target holds the files its submodule's filters
+    /// select from the source, with matching sizes
+    Verify {
+        /// Comma-separated submodule names to check (defaults to all)
+        #[arg(short, long)]
+        submodules: Option<String>,
+        /// Compare SHA-256 hashes of the file contents instead of sizes
+        #[arg(long, alias = "checksum-report")]
+        checksum: bool,
+    },
+    /// Print the configured submodules and their patterns
+    List {
+        /// Print the loaded config as JSON instead of a table
+        #[arg(long, conflicts_with = "tree")]
+        json: bool,
+        /// Print submodules as a tree grouped by the directory their target
+        /// is in, with each source -> target mapping
+        #[arg(long)]
+        tree: bool,
+        /// When to color the --tree output
+        #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+        color: ColorChoice,
+    },
+    /// Evaluate include/exclude patterns against sample paths with rsync's
+    /// matching rules, without a source tree. Includes are checked before
+    /// excludes, as in a submodule's config
+    PatternTest {
+        /// Include pattern (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+        /// Exclude pattern (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// Sample path, relative to the source; end it with / for a
+        /// directory (repeatable)
+        #[arg(long = "path", value_name = "PATH", required = true)]
+        paths: Vec<String>,
+    },
+    /// Show recent sync runs and each submodule's outcome, newest first
+    History {
+        /// Number of runs to show
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Show how many files each submodule's sync would create, update and
+    /// delete, without changing anything
+    Status,
+    /// Cross-check the config against the directory structure on disk
+    VerifyConfig,
+    /// Like verify-config, but exit non-zero on any problem found, warnings
+    /// such as empty include or exclude lists included
+    Validate,
+    /// Inspect the monorepo config
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Diagnose common configuration problems
+    Doctor {
+        /// Repair the problems found: create missing targets, normalize
+        /// patterns and remove submodules whose source is missing
+        #[arg(long)]
+        fix: bool,
+        /// Apply destructive repairs without asking for confirmation
+        #[arg(short, long, requires = "fix")]
+        yes: bool,
+    },
+    /// Copy a submodule's files kept by `sync --backup-dir` back over its target
+    Restore {
+        /// Submodule to restore
+        submodule: String,
+        /// Backup directory that was passed to `sync --backup-dir`
+        #[arg(long, value_name = "DIR", value_parser = sync::parse_backup_dir)]
+        backup_dir: PathBuf,
+        /// Restore without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Print the rsync command sync would run for one submodule, without running it
+    ShowCommand {
+        /// Submodule to show the command for
+        submodule: String,
+        #[command(flatten)]
+        options: Box<SyncOptions>,
+    },
+    /// Time rsync flag combinations on a generated dataset in a temporary
+    /// directory, to see which options help on this machine
+    #[command(hide = true)]
+    Bench {
+        /// Number of files to generate
+        #[arg(long, default_value_t = 100)]
+        files: usize,
+        /// Size of each generated file, e.g. 512K or 4M
+        #[arg(long, default_value = "1M", value_parser = sync::parse_size)]
+        file_size: u64,
+    },
+    /// Print the configured submodule names, one per line, for shell
+    /// completion scripts
+    #[command(name = "__complete-submodules", hide = true)]
+    CompleteSubmodules,
+    /// Check for a newer release of monorepo-agent
+    Update {
+        /// Release metadata URL (GitHub "latest release" JSON format)
+        #[arg(long, default_value = update::DEFAULT_RELEASE_URL)]
+        url: String,
+        /// Download the new release and replace the current binary
+        #[arg(long)]
+        apply: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print MONOREPO_SUBMODULE_<NAME>_PATH and _TARGET assignments for
+    /// `eval` or `source` in shell scripts
+    Env,
+    /// Check that the config parses, that submodule names are unique and
+    /// safe and that every submodule has include patterns, without touching
+    /// sources or targets or running rsync
+    Lint,
+    /// Rewrite config.json with every optional submodule field written out,
+    /// filling in defaults for fields added in newer versions
+    Upgrade,
+}
+
+/// When to use ANSI colors in output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    /// Color when stdout is a terminal and NO_COLOR is unset.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Exit code of `sync --dry-run --detect-changes` when a target would change.
+const EXIT_DRIFT: i32 = 2;
+
+/// Splits a comma-separated list of submodule names, rejecting empty entries.
+fn parse_submodule_names(list: &str) -> io::Result<Vec<String>> {
+    let names: Vec<String> = list.split(',').map(|s| s.trim().to_string()).collect();
+    if names.iter().any(|name| name.is_empty()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Submodule list must not be empty or contain empty names",
+        ));
+    }
+    Ok(names)
+}
+
+/// Returns the `path` of every git submodule listed in `.gitmodules` under
+/// `root`, or nothing when there is no such file.
+fn gitmodule_paths(root: &Path) -> io::Result<Vec<String>> {
+    let contents = match fs::read_to_string(root.join(".gitmodules")) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "path").then(|| value.trim().to_string())
+        })
+        .collect())
+}
+
+fn init_monorepo(
+    submodules: Option<&str>,
+    from_gitmodules: bool,
+    keep_order: bool,
+    all_files: bool,
+    init_from: Option<&Path>,
+    commit_message: Option<&str>,
+    verbosity: Verbosity,
+) -> io::Result<()> {
+    config::ensure_writable()?;
+    let quiet = verbosity == Verbosity::Quiet;
+    // Pairs of submodule name and source path.
+    let mut entries: Vec<(String, String)> = match submodules {
+        Some(list) => parse_submodule_names(list)?
+            .into_iter()
+            .map(|name| (name.clone(), name))
+            .collect(),
+        None => Vec::new(),
+    };
+    // init creates the monorepo here rather than looking for one further up.
+    let current_dir = std::env::current_dir()?;
+    let git_submodules = gitmodule_paths(&current_dir)?;
+    if !git_submodules.is_empty() && !quiet {
+        println!(
+            "Note: .gitmodules lists git submodules; the submodules this tool syncs are \
+             independent of them."
+        );
+        for path in &git_submodules {
+            println!("  git submodule: {}", path);
+        }
+        if !from_gitmodules {
+            println!("Pass --from-gitmodules to add them as synced submodules.");
+        }
+    }
+    if from_gitmodules {
+        if git_submodules.is_empty() {
+            eprintln!("Warning: --from-gitmodules: no git submodules listed in .gitmodules.");
+        }
+        for path in git_submodules {
+            let name = Path::new(&path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.clone());
+            if !entries.iter().any(|(existing, _)| *existing == name) {
+                entries.push((name, path));
+            }
+        }
+    }
+    let config_dir = current_dir.join(config::CONFIG_DIR);
+    config::check_config_dir(&config_dir)?;
+
+    // Read before anything is created, so a broken seed leaves no trace.
+    let seeded = init_from.map(config::load_seed_config).transpose()?;
+    if !config_dir.exists() {
+        fs::create_dir(&config_dir)?;
+        if !quiet {
+            println!("Initialized monorepo in {:?}", display::path(&config_dir));
+        }
+    }
+
+    let mut app_config = match seeded {
+        Some(seeded) => {
+            if config_dir.join(config::CONFIG_FILE).exists()
+                || config_dir.join(config::SUBMODULES_DIR).exists()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "The monorepo already has a config; --init-from only seeds a new one",
+                ));
+            }
+            if let (Some(seed), false) = (init_from, quiet) {
+                println!("Seeded config from {:?}", display::path(seed));
+            }
+            seeded
+        }
+        None => config::load_or_create_config(&config_dir)?,
+    };
+    if keep_order {
+        app_config.keep_order = true;
+    }
+    let default_template =
+        app_config.templates.contains_key(config::DEFAULT_TEMPLATE) && !all_files;
+    let target_base = app_config.target_base(&current_dir).ok();
+    for (name, path) in &entries {
+        if app_config.find(name).is_some() {
+            if !quiet {
+                println!("Submodule '{}' already configured.", name);
+            }
+            continue;
+        }
+
+        let mut submodule = if all_files {
+            SubmoduleConfig::mirror(name)
+        } else {
+            SubmoduleConfig::new(name)
+        };
+        submodule.path = path.clone();
+        if default_template {
+            submodule.extends = Some(config::DEFAULT_TEMPLATE.to_string());
+            submodule.include = None;
+            submodule.exclude = None;
+        }
+        let source = app_config.source_path(&current_dir, &submodule);
+        if !source.is_dir() {
+            eprintln!(
+                "Warning: source directory {:?} does not exist.",
+                display::path(&source)
+            );
+        }
+        if let Some(target_base) = &target_base {
+            let target = target_base.join(name);
+            if !target.is_dir() && !quiet {
+                println!(
+                    "Target directory {:?} does not exist yet; it will be created on first sync.",
+                    display::path(&target)
+                );
+            }
+        }
+
+        app_config.submodules.push(submodule);
+        if !quiet {
+            println!("Added submodule: {}", name);
+        }
+    }
+
+    config::save_config(&config_dir, &app_config)?;
+    if let Some(message) = commit_message {
+        config::commit_config(&current_dir, message, quiet)?;
+    }
+    Ok(())
+}
+
+fn remove_submodules(
+    submodules: &str,
+    commit_message: Option<&str>,
+    quiet: bool,
+) -> io::Result<()> {
+    config::ensure_writable()?;
+    let names = parse_submodule_names(submodules)?;
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let mut app_config = config::load_or_create_config(&config_dir)?;
+
+    let mut removed = Vec::new();
+    for name in &names {
+        if app_config.find(name).is_none() {
+            println!("Submodule '{}' not found in config.", name);
+            continue;
+        }
+        app_config.submodules.retain(|s| &s.name != name);
+        if !quiet {
+            println!("Removed submodule: {}", name);
+        }
+        removed.push(name);
+    }
+    if removed.is_empty() {
+        return Ok(());
+    }
+
+    config::save_config(&config_dir, &app_config)?;
+    if let Some(message) = commit_message {
+        config::commit_config(&root, message, quiet)?;
+    }
+    Ok(())
+}
+
+/// Renames submodule `old` to `new`, along with its `path` when that still
+/// equals the old name. Its sync state moves with it, and with
+/// `move_target` so does its sibling target directory.
+fn rename_submodule(
+    old: &str,
+    new: &str,
+    move_target: bool,
+    commit_message: Option<&str>,
+    quiet: bool,
+) -> io::Result<()> {
+    config::ensure_writable()?;
+    config::validate_name(new).map_err(|reason| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid submodule name '{}': {}", new, reason),
+        )
+    })?;
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let mut app_config = config::load_or_create_config(&config_dir)?;
+    if app_config.find(new).is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Submodule '{}' already exists in config", new),
+        ));
+    }
+    let Some(submodule) = app_config.submodules.iter_mut().find(|s| s.name == old) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Submodule '{}' not found in config", old),
+        ));
+    };
+    submodule.name = new.to_string();
+    if submodule.path == old {
+        submodule.path = new.to_string();
+    }
+    let custom_target = submodule.target.is_some() || submodule.target_command.is_some();
+
+    let mut sync_state = state::load_state(&config_dir)?;
+    if let Some(last_sync) = sync_state.last_sync.remove(old) {
+        sync_state.last_sync.insert(new.to_string(), last_sync);
+    }
+    if move_target {
+        let target_base = app_config.target_base(&root)?;
+        let (old_target, new_target) = (target_base.join(old), target_base.join(new));
+        if custom_target {
+            println!(
+                "Submodule '{}' has a custom target; the target directory was not moved.",
+                new
+            );
+        } else if new_target.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "Cannot move the target: {:?} already exists",
+                    display::path(&new_target)
+                ),
+            ));
+        } else if old_target.is_dir() {
+            fs::rename(&old_target, &new_target)?;
+            if sync_state.created_targets.remove(&old_target) {
+                sync_state.created_targets.insert(new_target.clone());
+            }
+            if !quiet {
+                println!(
+                    "Moved {:?} to {:?}",
+                    display::path(&old_target),
+                    display::path(&new_target)
+                );
+            }
+        } else {
+            println!(
+                "Target directory {:?} does not exist; nothing to move.",
+                display::path(&old_target)
+            );
+        }
+    }
+
+    config::save_config(&config_dir, &app_config)?;
+    state::save_state(&config_dir, &sync_state)?;
+    if !quiet {
+        println!("Renamed submodule {} to {}", old, new);
+    }
+    if let Some(message) = commit_message {
+        config::commit_config(&root, message, quiet)?;
+    }
+    Ok(())
+}
+
+/// Splits a comma-separated pattern list; an empty list clears the patterns.
+fn parse_patterns(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn set_patterns(
+    name: &str,
+    include: Option<&str>,
+    exclude: Option<&str>,
+    commit_message: Option<&str>,
+    quiet: bool,
+) -> io::Result<()> {
+    if include.is_none() && exclude.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Pass --include and/or --exclude to set patterns",
+        ));
+    }
+    config::ensure_writable()?;
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let mut app_config = config::load_or_create_config(&config_dir)?;
+    let submodule = app_config
+        .submodules
+        .iter_mut()
+        .find(|s| s.name == name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Submodule '{}' not found in config", name),
+            )
+        })?;
+    if let Some(include) = include {
+        submodule.include = Some(parse_patterns(include));
+    }
+    if let Some(exclude) = exclude {
+        submodule.exclude = Some(parse_patterns(exclude));
+    }
+    let show = |patterns: &[String]| match patterns {
+        [] => "(none)".to_string(),
+        patterns => patterns.join(", "),
+    };
+    if !quiet {
+        println!("Updated patterns of {}:", name);
+        println!("  include: {}", show(submodule.include()));
+        println!("  exclude: {}", show(submodule.exclude()));
+    }
+
+    config::save_config(&config_dir, &app_config)?;
+    if let Some(message) = commit_message {
+        config::commit_config(&root, message, quiet)?;
+    }
+    Ok(())
+}
+
+/// How `sync` maps its summary to an exit code.
+struct SyncExit {
+    fail_on_empty: bool,
+    on_changes: i32,
+    on_failure: i32,
+    detect_changes: bool,
+    /// Changes a dry run reports were not applied, so they only count
+    /// for `--detect-changes`.
+    dry_run: bool,
+}
+
+impl SyncExit {
+    /// The exit code for a finished run, reporting failures on stderr.
+    fn code(&self, summary: &SyncSummary) -> i32 {
+        if self.fail_on_empty && summary.selected == 0 {
+            eprintln!("No submodules to sync (--fail-on-empty).");
+            return self.on_failure;
+        }
+        if summary.failed > 0 || summary.delete_limited > 0 {
+            if summary.failed > 0 {
+                let failures: Vec<String> = summary
+                    .results
+                    .iter()
+                    .filter(|r| {
+                        r.outcome.is_failure() && r.outcome != SyncOutcome::DeleteLimitExceeded
+                    })
+                    .map(|r| match r.exit_code {
+                        Some(code) => {
+                            format!("{} ({}, rsync exit {})", r.name, r.outcome.as_str(), code)
+                        }
+                        None => format!("{} ({})", r.name, r.outcome.as_str()),
+                    })
+                    .collect();
+                eprintln!(
+                    "{} of {} submodule(s) failed to sync: {}",
+                    summary.failed,
+                    summary.selected,
+                    failures.join(", ")
+                );
+            }
+            if summary.delete_limited > 0 {
+                eprintln!(
+                    "{} submodule(s) stopped by the --max-delete safety limit.",
+                    summary.delete_limited
+                );
+            }
+            return self.on_failure;
+        }
+        match summary.changed {
+            0 => 0,
+            _ if self.detect_changes => EXIT_DRIFT,
+            _ if self.dry_run => 0,
+            _ => self.on_changes,
+        }
+    }
+}
+
+/// Turns a submodule name into an environment variable name component:
+/// uppercase ASCII letters, digits and underscores, not starting with a digit.
+fn env_name(name: &str) -> String {
+    let mut env_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if env_name.starts_with(|c: char| c.is_ascii_digit()) {
+        env_name.insert(0, '_');
+    }
+    env_name
+}
+
+fn print_config_env() -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+    let mut seen = HashMap::new();
+    for plan in sync::plan_submodules(&root, &app_config, None)? {
+        let name = env_name(&plan.submodule.name);
+        if let Some(other) = seen.insert(name.clone(), &plan.submodule.name) {
+            eprintln!(
+                "Warning: '{}' and '{}' both map to MONOREPO_SUBMODULE_{}; the later one wins.",
+                other, plan.submodule.name, name
+            );
+        }
+        println!(
+            "MONOREPO_SUBMODULE_{}_PATH={}",
+            name,
+            rsync::shell_quote(&plan.source_path.to_string_lossy())
+        );
+        println!(
+            "MONOREPO_SUBMODULE_{}_TARGET={}",
+            name,
+            rsync::shell_quote(&plan.target_path.to_string_lossy())
+        );
+    }
+    Ok(())
+}
+
+/// Prints one row per configured submodule, the whole config as JSON, or a
+/// tree of submodules grouped by target base.
+fn list_submodules(json: bool, tree: bool, color: ColorChoice) -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&app_config)?);
+        return Ok(());
+    }
+    if app_config.submodules.is_empty() {
+        println!("No submodules configured.");
+        return Ok(());
+    }
+    if tree {
+        return print_tree(&root, &app_config, color.enabled());
+    }
+
+    let patterns = |patterns: &[String]| match patterns {
+        [] => "-".to_string(),
+        patterns => patterns.join(", "),
+    };
+    let rows: Vec<[String; 5]> = app_config
+        .submodules
+        .iter()
+        .map(|s| {
+            [
+                s.name.clone(),
+                s.path.clone(),
+                patterns(s.include()),
+                patterns(s.exclude()),
+                match &s.meta {
+                    serde_json::Value::Null => "-".to_string(),
+                    meta => meta.to_string(),
+                },
+            ]
+        })
+        .collect();
+    let header = ["NAME", "PATH", "INCLUDE", "EXCLUDE", "META"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: [&str; 5]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header);
+    for row in &rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+    Ok(())
+}
+
+/// Prints each target base directory followed by the submodules syncing into
+/// it, as `name: source -> target` with the target relative to the base.
+fn print_tree(root: &Path, app_config: &config::AppConfig, color: bool) -> io::Result<()> {
+    let paint = |code: &str, text: &str| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    };
+    let mut bases: BTreeMap<PathBuf, Vec<sync::SyncPlan>> = BTreeMap::new();
+    for plan in sync::plan_submodules(root, app_config, None)? {
+        // Unresolved target_command targets are grouped on their own and
+        // shown as the command.
+        let base = match plan.target_unresolved {
+            true => PathBuf::from("target_command"),
+            false => plan
+                .target_path
+                .parent()
+                .unwrap_or(Path::new(""))
+                .to_path_buf(),
+        };
+        bases.entry(base).or_default().push(plan);
+    }
+    for (base, plans) in &bases {
+        println!("{}", paint("1", &base.display().to_string()));
+        for (i, plan) in plans.iter().enumerate() {
+            let branch = if i + 1 == plans.len() {
+                "└──"
+            } else {
+                "├──"
+            };
+            let source = plan
+                .source_path
+                .strip_prefix(root)
+                .unwrap_or(&plan.source_path);
+            let target = plan
+                .target_path
+                .strip_prefix(base)
+                .unwrap_or(&plan.target_path);
+            println!(
+                "{} {}: {}/ -> {}/",
+                branch,
+                paint("36", &plan.submodule.name),
+                source.display(),
+                target.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints whether each sample path would be synced and which rule decided.
+fn pattern_test(include: &[String], exclude: &[String], paths: &[String]) {
+    let rules: Vec<pattern::Rule> = include
+        .iter()
+        .map(|p| (true, p))
+        .chain(exclude.iter().map(|p| (false, p)))
+        .map(|(include, pattern)| pattern::Rule { include, pattern })
+        .collect();
+    let width = paths.iter().map(String::len).max().unwrap_or(0);
+    for path in paths {
+        let verdict = pattern::decide(&rules, path, path.ends_with('/'));
+        let reason = match (&verdict.rule, &verdict.excluded_dir) {
+            (Some(rule), Some(dir)) => {
+                format!("inside {}, excluded by '{}'", dir, rule.pattern)
+            }
+            (Some(rule), None) if rule.include => format!("include '{}'", rule.pattern),
+            (Some(rule), None) => format!("exclude '{}'", rule.pattern),
+            (None, _) => "no rule matched".to_string(),
+        };
+        println!(
+            "{}  {:width$}  ({})",
+            if verdict.included {
+                "include"
+            } else {
+                "exclude"
+            },
+            path,
+            reason,
+            width = width
+        );
+    }
+}
+
+/// Prints just the submodule names for completion functions. Outside a
+/// monorepo, or with an unreadable config, it prints nothing, since errors
+/// would end up in the user's command line.
+fn complete_submodules() {
+    let Ok(root) = config::find_root() else {
+        return;
+    };
+    let config_dir = root.join(config::CONFIG_DIR);
+    if let Ok(app_config) = config::load_or_create_config(&config_dir) {
+        for submodule in &app_config.submodules {
+            println!("{}", submodule.name);
+        }
+    }
+}
+
+fn upgrade_config() -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let added = config::upgrade_config(&config_dir)?;
+    if added.is_empty() {
+        println!("Config already lists every field.");
+    }
+    for (name, fields) in &added {
+        println!("{}: added {}", name, fields.join(", "));
+    }
+    Ok(())
+}
+
+/// Copies `options` with the global verbosity and the `--exclude-from-stdin`
+/// patterns filled in. `--json-lines` owns stdout, so it silences the usual
+/// messages.
+fn prepare_options(options: &SyncOptions, verbosity: Verbosity) -> io::Result<SyncOptions> {
+    let mut options = options.clone();
+    options.verbosity = if options.json_lines {
+        Verbosity::Quiet
+    } else {
+        verbosity
+    };
+    if options.exclude_from_stdin {
+        options.stdin_excludes = sync::read_stdin_excludes()?;
+    }
+    Ok(options)
+}
+
+/// Prints one line per submodule after a run that processed several.
+fn print_results(summary: &SyncSummary) {
+    if summary.results.len() < 2 {
+        return;
+    }
+    let width = summary
+        .results
+        .iter()
+        .map(|r| r.name.len())
+        .max()
+        .unwrap_or(0);
+    println!("Summary:");
+    for result in &summary.results {
+        println!(
+            "  {:width$}  {:<24} {:>5} change(s) {:>8.2}s",
+            result.name,
+            result.outcome.as_str(),
+            result.changes,
+            result.elapsed.as_secs_f64(),
+            width = width
+        );
+    }
+}
+
+fn run_sync(
+    submodules: Option<&str>,
+    options: &SyncOptions,
+    verbosity: Verbosity,
+) -> io::Result<SyncSummary> {
+    let selected = submodules.map(parse_submodule_names).transpose()?;
+    let options = prepare_options(options, verbosity)?;
+    let (summary, error) = match sync::sync_submodules(selected.as_deref(), &options) {
+        Ok(summary) => (summary, None),
+        Err(aborted) => (aborted.summary, Some(aborted.error)),
+    };
+    if options.verbosity > Verbosity::Quiet {
+        print_results(&summary);
+    }
+    // The reports are written even when the run stopped early, and one
+    // failing does not keep the other from being written.
+    let written = [
+        options
+            .output_dir
+            .as_ref()
+            .map(|output_dir| sync::write_summary(output_dir, &summary)),
+        options
+            .summary_json_file
+            .as_ref()
+            .map(|path| sync::write_summary_file(path, &summary)),
+    ];
+    let mut write_errors = written.into_iter().flatten().filter_map(Result::err);
+    match error {
+        Some(error) => {
+            for e in write_errors {
+                eprintln!("Error: cannot write the run summary: {}", e);
+            }
+            Err(error)
+        }
+        None => match write_errors.next() {
+            Some(e) => Err(e),
+            None => Ok(summary),
+        },
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if cli.config_readonly {
+        config::set_readonly();
+    }
+    if cli.relative_paths {
+        if let Ok(base) = config::find_root().or_else(|_| std::env::current_dir()) {
+            display::set_relative_base(base);
+        }
+    }
+
+    let verbosity = cli.verbosity();
+    let result = match &cli.command {
+        Commands::Init {
+            submodules,
+            from_gitmodules,
+            keep_order,
+            all_files,
+            init_from,
+            commit_config,
+        } => init_monorepo(
+            submodules.as_deref(),
+            *from_gitmodules,
+            *keep_order,
+            *all_files,
+            init_from.as_deref(),
+            commit_config.as_deref(),
+            verbosity,
+        )
+        .map(|()| 0),
+        Commands::Remove {
+            submodules,
+            commit_config,
+        } => remove_submodules(submodules, commit_config.as_deref(), cli.quiet).map(|()| 0),
+        Commands::Rename {
+            old,
+            new,
+            move_target,
+            commit_config,
+        } => rename_submodule(old, new, *move_target, commit_config.as_deref(), cli.quiet)
+            .map(|()| 0),
+        Commands::SetPatterns {
+            submodule,
+            include,
+            exclude,
+            commit_config,
+        } => set_patterns(
+            submodule,
+            include.as_deref(),
+            exclude.as_deref(),
+            commit_config.as_deref(),
+            cli.quiet,
+        )
+        .map(|()| 0),
+        Commands::Sync {
+            submodules,
+            options,
+            fail_on_empty,
+            exit_code_on_changes,
+            exit_code_on_failure,
+            detect_changes,
+        } => {
+            let exit = SyncExit {
+                fail_on_empty: *fail_on_empty,
+                on_changes: *exit_code_on_changes,
+                on_failure: *exit_code_on_failure,
+                detect_changes: *detect_changes,
+                dry_run: options.dry_run || options.quiet_dry_run,
+            };
+            match run_sync(submodules.as_deref(), options, verbosity) {
+                Ok(summary) => Ok(exit.code(&summary)),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    Ok(exit.on_failure)
+                }
+            }
+        }
+        Commands::Watch {
+            submodules,
+            options,
+            interval,
+            watch_initial_sync,
+        } => submodules
+            .as_deref()
+            .map(parse_submodule_names)
+            .transpose()
+            .and_then(|selected| {
+                let options = prepare_options(options, verbosity)?;
+                watch::watch(
+                    selected.as_deref(),
+                    &options,
+                    Duration::from_secs(*interval),
+                    *watch_initial_sync,
+                )
+            })
+            .map(|()| 0),
+        Commands::Verify {
+            submodules,
+            checksum,
+        } => submodules
+            .as_deref()
+            .map(parse_submodule_names)
+            .transpose()
+            .and_then(|selected| verify::verify_targets(selected.as_deref(), *checksum))
+            .map(|()| 0),
+        Commands::List { json, tree, color } => list_submodules(*json, *tree, *color).map(|()| 0),
+        Commands::PatternTest {
+            include,
+            exclude,
+            paths,
+        } => {
+            pattern_test(include, exclude, paths);
+            Ok(0)
+        }
+        Commands::History { limit } => history::history(*limit).map(|()| 0),
+        Commands::Status => sync::status().map(|()| 0),
+        Commands::VerifyConfig => verify::verify_config(false).map(|()| 0),
+        Commands::Validate => verify::verify_config(true).map(|()| 0),
+        Commands::Config { command } => match command {
+            ConfigCommand::Env => print_config_env().map(|()| 0),
+            ConfigCommand::Lint => verify::lint_config().map(|()| 0),
+            ConfigCommand::Upgrade => upgrade_config().map(|()| 0),
+        },
+        Commands::Doctor { fix, yes } => doctor::doctor(*fix, *yes).map(|()| 0),
+        Commands::Restore {
+            submodule,
+            backup_dir,
+            yes,
+        } => restore::restore(submodule, backup_dir, *yes).map(|()| 0),
+        Commands::ShowCommand { submodule, options } => prepare_options(options, verbosity)
+            .and_then(|options| sync::show_command(submodule, &options))
+            .map(|()| 0),
+        Commands::Bench { files, file_size } => bench::bench(*files, *file_size).map(|()| 0),
+        Commands::CompleteSubmodules => {
+            complete_submodules();
+            Ok(0)
+        }
+        Commands::Update { url, apply } => update::check_for_update(url, *apply).map(|()| 0),
+    };
+
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exit() -> SyncExit {
+        SyncExit {
+            fail_on_empty: false,
+            on_changes: 3,
+            on_failure: 4,
+            detect_changes: false,
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn sync_exit_code_reports_changes_only_when_applied() {
+        let mut summary = SyncSummary::default();
+        summary.selected = 1;
+        summary.synced = 1;
+        summary.changed = 1;
+        assert_eq!(exit().code(&summary), 3);
+        let dry_run = SyncExit {
+            dry_run: true,
+            ..exit()
+        };
+        assert_eq!(dry_run.code(&summary), 0);
+        let detect = SyncExit {
+            detect_changes: true,
+            ..dry_run
+        };
+        assert_eq!(detect.code(&summary), EXIT_DRIFT);
+        assert_eq!(exit().code(&SyncSummary::default()), 0);
+        let fail_on_empty = SyncExit {
+            fail_on_empty: true,
+            ..exit()
+        };
+        assert_eq!(fail_on_empty.code(&SyncSummary::default()), 4);
+    }
+
+    #[test]
+    fn env_name_makes_a_valid_variable_component() {
+        assert_eq!(env_name("my-app"), "MY_APP");
+        assert_eq!(env_name("web.admin"), "WEB_ADMIN");
+        assert_eq!(env_name("2fa-service"), "_2FA_SERVICE");
+        assert_eq!(env_name("café"), "CAF_");
+    }
+
+    #[test]
+    fn max_delete_stop_exits_with_the_failure_code() {
+        let outcome = sync::failure_outcome(Some(rsync::EXIT_MAX_DELETE));
+        assert_eq!(outcome, SyncOutcome::DeleteLimitExceeded);
+        let mut summary = SyncSummary::default();
+        summary.selected = 1;
+        summary.count_failure(outcome);
+        assert_eq!((summary.failed, summary.delete_limited), (0, 1));
+        assert_eq!(exit().code(&summary), 4);
+    }
+}
@@ -0,0 +1,247 @@
+//! In-process evaluation of rsync include/exclude patterns, following the
+//! rules in rsync(1) "INCLUDE/EXCLUDE PATTERN RULES":
+//!
+//! - a leading `/` anchors the pattern at the transfer root;
+//! - a trailing `/` only matches directories;
+//! - a pattern without `/` (ignoring a trailing one) or `**` matches the
+//!   final path component, otherwise the full path (unanchored patterns may
+//!   match starting at any directory boundary);
+//! - `*` matches within a component, `**` across components, `?` one
+//!   non-slash character and `[...]` a character class;
+//! - `dir/***` matches `dir` itself and everything below it.
+
+/// Returns whether `path` (relative to the transfer root, `/`-separated)
+/// matches `pattern`. `is_dir` tells whether the path is a directory.
+pub fn matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+    if let Some(dir) = pattern.strip_suffix("/***") {
+        return matches(&format!("{}/", dir), path, is_dir)
+            || matches(&format!("{}/**", dir), path, is_dir);
+    }
+
+    let (anchored, pattern) = match pattern.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, pattern),
+    };
+    let pattern = match pattern.strip_suffix('/') {
+        Some(rest) => {
+            if !is_dir {
+                return false;
+            }
+            rest
+        }
+        None => pattern,
+    };
+    let path = path.trim_matches('/');
+
+    if anchored {
+        return glob(pattern.as_bytes(), path.as_bytes());
+    }
+    if !pattern.contains('/') && !pattern.contains("**") {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        return glob(pattern.as_bytes(), name.as_bytes());
+    }
+    glob(pattern.as_bytes(), path.as_bytes())
+        || path
+            .match_indices('/')
+            .any(|(i, _)| glob(pattern.as_bytes(), &path.as_bytes()[i + 1..]))
+}
+
+/// One include or exclude rule of a filter list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule<'a> {
+    pub include: bool,
+    pub pattern: &'a str,
+}
+
+/// How a filter list treats a path.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verdict<'a> {
+    pub included: bool,
+    /// The first rule that matched, or `None` when none did and the path is
+    /// included by default.
+    pub rule: Option<Rule<'a>>,
+    /// The ancestor directory whose exclusion hides the path, since rsync
+    /// never descends into an excluded directory.
+    pub excluded_dir: Option<String>,
+}
+
+/// Evaluates `rules` against `path` the way rsync does: the first matching
+/// rule wins, checked for each ancestor directory first and then for the
+/// path itself.
+pub fn decide<'a>(rules: &[Rule<'a>], path: &str, is_dir: bool) -> Verdict<'a> {
+    let path = path.trim_matches('/');
+    let first_match = |path: &str, is_dir: bool| {
+        rules
+            .iter()
+            .copied()
+            .find(|rule| matches(rule.pattern, path, is_dir))
+    };
+    for (i, _) in path.match_indices('/') {
+        let dir = &path[..i];
+        if let Some(rule) = first_match(dir, true).filter(|rule| !rule.include) {
+            return Verdict {
+                included: false,
+                rule: Some(rule),
+                excluded_dir: Some(format!("{}/", dir)),
+            };
+        }
+    }
+    let rule = first_match(path, is_dir);
+    Verdict {
+        included: rule.is_none_or(|rule| rule.include),
+        rule,
+        excluded_dir: None,
+    }
+}
+
+/// Matches `text` against a wildcard pattern using rsync's wildcard rules.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => {
+            if pattern.get(1) == Some(&b'*') {
+                let rest = &pattern[2..];
+                (0..=text.len()).any(|i| glob(rest, &text[i..]))
+            } else {
+                let rest = &pattern[1..];
+                let limit = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+                (0..=limit).any(|i| glob(rest, &text[i..]))
+            }
+        }
+        Some(b'?') => match text.first() {
+            Some(&c) if c != b'/' => glob(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => match (text.first(), class_end(pattern)) {
+            (Some(&c), Some(end)) if c != b'/' => {
+                class_matches(&pattern[1..end], c) && glob(&pattern[end + 1..], &text[1..])
+            }
+            (Some(&c), None) => c == b'[' && glob(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob(&pattern[2..], &text[1..])
+        }
+        Some(&p) => text.first() == Some(&p) && glob(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Index of the `]` closing the character class that starts `pattern`.
+fn class_end(pattern: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    if matches!(pattern.get(i), Some(b'!' | b'^')) {
+        i += 1;
+    }
+    // A `]` right after the opening bracket is a literal member.
+    if pattern.get(i) == Some(&b']') {
+        i += 1;
+    }
+    pattern[i..].iter().position(|&c| c == b']').map(|p| p + i)
+}
+
+/// Tests `c` against the members of a character class (without brackets).
+fn class_matches(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!' | b'^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            found |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leading_slash_anchors_at_the_root() {
+        assert!(matches("/build", "build", true));
+        assert!(!matches("/build", "app/build", true));
+        assert!(matches("build", "app/build", true));
+        assert!(matches("build", "build", false));
+    }
+
+    #[test]
+    fn trailing_slash_matches_only_directories() {
+        assert!(matches("build/", "build", true));
+        assert!(!matches("build/", "build", false));
+        assert!(matches("build/", "app/build", true));
+    }
+
+    #[test]
+    fn single_star_stays_within_a_component() {
+        assert!(matches("lib/*.dart", "lib/main.dart", false));
+        assert!(!matches("lib/*.dart", "lib/src/main.dart", false));
+        assert!(matches("lib/**.dart", "lib/src/main.dart", false));
+        assert!(matches("*.log", "logs/deep/app.log", false));
+        assert!(!matches("a?b", "a/b", false));
+    }
+
+    #[test]
+    fn triple_star_matches_the_directory_and_its_contents() {
+        assert!(matches("lib/***", "lib", true));
+        assert!(matches("lib/***", "lib/src/main.dart", false));
+        assert!(!matches("lib/***", "library", true));
+        assert!(!matches("lib/***", "library/main.dart", false));
+    }
+
+    #[test]
+    fn character_classes_and_escapes() {
+        assert!(matches("[abc].txt", "b.txt", false));
+        assert!(!matches("[abc].txt", "d.txt", false));
+        assert!(!matches("[!abc].txt", "b.txt", false));
+        assert!(matches("[^abc].txt", "d.txt", false));
+        assert!(matches("[a-c].txt", "c.txt", false));
+        assert!(matches("[]x].txt", "].txt", false));
+        assert!(matches(r"\*.txt", "*.txt", false));
+        assert!(!matches(r"\*.txt", "a.txt", false));
+        assert!(matches(r"\[a].txt", "[a].txt", false));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_match_the_full_path() {
+        assert!(matches("src/main.rs", "src/main.rs", false));
+        assert!(matches("src/main.rs", "app/src/main.rs", false));
+        assert!(!matches("src/main.rs", "main.rs", false));
+        assert!(!matches("src/main.rs", "mysrc/main.rs", false));
+        assert!(!matches("/src/main.rs", "app/src/main.rs", false));
+    }
+
+    #[test]
+    fn first_matching_rule_wins_and_excluded_directories_hide_their_contents() {
+        let rules = [
+            Rule {
+                include: true,
+                pattern: "lib/***",
+            },
+            Rule {
+                include: true,
+                pattern: "pubspec.yaml",
+            },
+            Rule {
+                include: false,
+                pattern: "*",
+            },
+        ];
+        assert!(decide(&rules, "lib/src/main.dart", false).included);
+        assert!(decide(&rules, "pubspec.yaml", false).included);
+
+        let verdict = decide(&rules, "build/app.o", false);
+        assert!(!verdict.included);
+        assert_eq!(verdict.excluded_dir.as_deref(), Some("build/"));
+
+        let verdict = decide(&[], "anything", false);
+        assert!(verdict.included);
+        assert_eq!(verdict.rule, None);
+    }
+}
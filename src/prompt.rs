@@ -0,0 +1,52 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Asks a yes/no question on stdin. `assume_yes` (from `--yes`) answers it
+/// without prompting; without it, a non-interactive stdin is an error rather
+/// than a silent "yes".
+pub fn confirm(question: &str, assume_yes: bool) -> io::Result<bool> {
+    if assume_yes {
+        return Ok(true);
+    }
+    Ok(matches!(ask(question, "[y/N]")?.as_str(), "y" | "yes"))
+}
+
+/// An answer to `choose`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    Yes,
+    No,
+    /// Yes to this and every remaining question.
+    All,
+    Abort,
+}
+
+/// Asks a question answered with yes, no, all or abort. Anything else,
+/// including an empty answer, aborts, and so does a non-interactive stdin.
+pub fn choose(question: &str) -> io::Result<Choice> {
+    Ok(match ask(question, "[y/n/a/q]")?.as_str() {
+        "y" | "yes" => Choice::Yes,
+        "n" | "no" => Choice::No,
+        "a" | "all" => Choice::All,
+        _ => Choice::Abort,
+    })
+}
+
+/// Prints `question` with its `hint` and returns the trimmed, lowercased
+/// answer, failing when stdin is not a terminal.
+fn ask(question: &str, hint: &str) -> io::Result<String> {
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Cannot ask \"{}\": stdin is not a terminal; pass --yes to confirm",
+                question
+            ),
+        ));
+    }
+
+    print!("{} {} ", question, hint);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_lowercase())
+}
@@ -0,0 +1,104 @@
+use crate::config;
+use crate::display;
+use crate::prompt;
+use crate::rsync::{self, ItemChange, RsyncCommand};
+use crate::sync;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// Copies the backup of `name` kept by `sync --backup-dir` back over its
+/// target. Nothing is deleted from the target; files that exist only there
+/// are left alone.
+pub fn restore(name: &str, backup_dir: &Path, yes: bool) -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+    if app_config.find(name).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Submodule '{}' not found in config", name),
+        ));
+    }
+
+    let selected = [name.to_string()];
+    let mut plans = sync::plan_submodules(&root, &app_config, Some(&selected))?;
+    let target_base = app_config.target_base(&root)?;
+    sync::resolve_target_commands(&mut plans, &root, &target_base, &mut HashMap::new())?;
+    let plan = &plans[0];
+    let backup = sync::backup_path(backup_dir, name);
+    if !backup.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No backup of '{}' found at {:?}",
+                name,
+                display::path(&backup)
+            ),
+        ));
+    }
+
+    let mut rsync_cmd = RsyncCommand::copy(plan.archive, &backup, &plan.target_path, false);
+    rsync_cmd.arg("--itemize-changes");
+    let mut preview = RsyncCommand::copy(plan.archive, &backup, &plan.target_path, false);
+    preview.arg("--itemize-changes").arg("--dry-run");
+    let (status, lines) = rsync::run_captured(&preview)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Restore preview for {} failed: rsync exited with {}",
+            name, status
+        )));
+    }
+
+    let (mut overwritten, mut restored) = (Vec::new(), 0);
+    for line in &lines {
+        match rsync::parse_itemized(line) {
+            Some((ItemChange::Updated, path)) => overwritten.push(path),
+            Some((ItemChange::Created, _)) => restored += 1,
+            _ => {}
+        }
+    }
+    if overwritten.is_empty() && restored == 0 {
+        println!(
+            "{:?} already matches the backup in {:?}.",
+            display::path(&plan.target_path),
+            display::path(&backup)
+        );
+        return Ok(());
+    }
+
+    println!(
+        "Restoring {} from {:?} into {:?}: {} missing file(s) recreated, {} overwritten.",
+        name,
+        display::path(&backup),
+        display::path(&plan.target_path),
+        restored,
+        overwritten.len()
+    );
+    if !overwritten.is_empty() {
+        eprintln!("WARNING: these target files will be overwritten:");
+        for path in &overwritten {
+            eprintln!("  {}", path);
+        }
+    }
+    if !prompt::confirm("Restore from the backup?", yes)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Restore cancelled by user",
+        ));
+    }
+
+    let (status, _) = rsync::run_captured(&rsync_cmd)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Failed to restore {}: rsync exited with {}",
+            name, status
+        )));
+    }
+    println!(
+        "Restored {} to {:?}",
+        name,
+        display::path(&plan.target_path)
+    );
+    Ok(())
+}
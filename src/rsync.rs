@@ -0,0 +1,722 @@
+use crate::config::{ArchiveFlags, SourceRules, SubmoduleConfig};
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::process::{Command as ProcessCommand, ExitStatus, Stdio};
+
+/// rsync exit code when `--max-delete` stopped deletions.
+pub const EXIT_MAX_DELETE: i32 = 25;
+
+/// rsync exit code when source files vanished during the transfer.
+pub const EXIT_VANISHED: i32 = 24;
+
+/// rsync exit code when no data moved for the `--timeout` period.
+pub const EXIT_TIMEOUT: i32 = 30;
+
+/// Checks that an `rsync` binary can be started, so a missing install is
+/// reported as such rather than as a bare "No such file or directory".
+pub fn check_available() -> io::Result<()> {
+    let probe = ProcessCommand::new("rsync")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match probe {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "rsync not found on PATH; please install it",
+        )),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("Failed to run rsync: {}", e),
+        )),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// An rsync invocation under construction. Flags added through `arg` are
+/// placed before the source and target paths, and filter rules added through
+/// `filter` take precedence over the submodule's own include/exclude rules.
+pub struct RsyncCommand {
+    args: Vec<OsString>,
+    run_filters: Vec<String>,
+    submodule_filters: Vec<String>,
+    delete_scope: Vec<String>,
+    sources: Vec<OsString>,
+    target: Option<OsString>,
+    /// Environment variables for the rsync process, kept out of `command()`
+    /// so values such as `RSYNC_PASSWORD` are not printed with it.
+    envs: Vec<(String, String)>,
+}
+
+impl RsyncCommand {
+    /// Starts the rsync command syncing `source` into `target` with the given
+    /// archive flags and the submodule's include/exclude rules.
+    pub fn new(
+        submodule: &SubmoduleConfig,
+        archive: &ArchiveFlags,
+        source: &Path,
+        target: &Path,
+        delete: bool,
+    ) -> Self {
+        let mut rsync_cmd = RsyncCommand::copy(archive, source, target, delete);
+        for pattern in submodule.include() {
+            rsync_cmd
+                .submodule_filters
+                .push(format!("--include={}", pattern));
+        }
+        for pattern in submodule.exclude() {
+            rsync_cmd
+                .submodule_filters
+                .push(format!("--exclude={}", pattern));
+        }
+        rsync_cmd
+    }
+
+    /// Starts an rsync command copying all of `source` into `target`, without
+    /// any submodule filters.
+    pub fn copy(archive: &ArchiveFlags, source: &Path, target: &Path, delete: bool) -> Self {
+        let mut rsync_cmd = RsyncCommand {
+            args: Vec::new(),
+            run_filters: Vec::new(),
+            submodule_filters: Vec::new(),
+            delete_scope: Vec::new(),
+            sources: vec![source_arg(source)],
+            target: Some(target_arg(target, false)),
+            envs: Vec::new(),
+        };
+        for flag in archive.args() {
+            rsync_cmd.arg(flag);
+        }
+        if delete {
+            rsync_cmd.arg("--delete");
+        }
+        rsync_cmd.arg("--inplace");
+        rsync_cmd
+    }
+
+    pub fn arg(&mut self, arg: impl Into<OsString>) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Removes a flag added earlier, e.g. one of the base flags that conflicts
+    /// with a requested option.
+    pub fn remove_arg(&mut self, arg: &str) -> &mut Self {
+        self.args.retain(|a| a != arg);
+        self
+    }
+
+    /// Adds a filter argument (e.g. `--exclude=*.log`) evaluated before the
+    /// submodule's rules, so it applies regardless of what they include.
+    pub fn filter(&mut self, rule: impl Into<String>) -> &mut Self {
+        self.run_filters.push(rule.into());
+        self
+    }
+
+    /// Applies rules from the submodule's `.monorepo-sync` file: they go
+    /// before the configured rules, or replace them.
+    pub fn source_rules(&mut self, rules: &SourceRules) -> &mut Self {
+        let mut filters: Vec<String> = rules
+            .include
+            .iter()
+            .map(|pattern| format!("--include={}", pattern))
+            .chain(
+                rules
+                    .exclude
+                    .iter()
+                    .map(|pattern| format!("--exclude={}", pattern)),
+            )
+            .collect();
+        if !rules.replace {
+            filters.append(&mut self.submodule_filters);
+        }
+        self.submodule_filters = filters;
+        self
+    }
+
+    /// Appends the config's global rules after the submodule's own, so the
+    /// submodule's rules win wherever both match a path. A submodule rule
+    /// matching everything, like the default `--exclude=*`, shadows them.
+    pub fn global_rules(&mut self, include: &[String], exclude: &[String]) -> &mut Self {
+        for pattern in include {
+            self.submodule_filters
+                .push(format!("--include={}", pattern));
+        }
+        for pattern in exclude {
+            self.submodule_filters
+                .push(format!("--exclude={}", pattern));
+        }
+        self
+    }
+
+    /// Limits `--delete` to target paths under `scopes`. Their risk rules and
+    /// a final protect-everything rule come after every other filter, so
+    /// excludes still shield the files inside a scope. The submodule's rules
+    /// turn sender-only: on the receiver their includes would expose files
+    /// outside the scopes, and a catch-all exclude would shield the scopes.
+    pub fn delete_scope(&mut self, scopes: &[String]) -> &mut Self {
+        self.delete_scope = scopes.to_vec();
+        self
+    }
+
+    /// Replaces the source with several directories inside it, each synced into
+    /// the target root: `--relative` keeps only the part of a path after the
+    /// `/./` marker, so `src/dist/./` publishes the contents of `dist`.
+    pub fn relative_sources(&mut self, source: &Path, dirs: &[String]) -> &mut Self {
+        self.sources = dirs
+            .iter()
+            .map(|dir| {
+                let mut arg = source.join(dir).into_os_string();
+                arg.push("/./");
+                arg
+            })
+            .collect();
+        self.arg("--relative")
+    }
+
+    /// Passes the target with a trailing slash (`--target-trailing-slash`)
+    /// or without one, the default. Since the source is always passed with
+    /// its slash, its contents land directly in the target either way; with
+    /// the slash rsync also insists the target is a directory, creating it
+    /// as one even when a single file is transferred.
+    pub fn target_trailing_slash(&mut self, trailing_slash: bool) -> &mut Self {
+        if let Some(target) = &self.target {
+            self.target = Some(target_arg(Path::new(target), trailing_slash));
+        }
+        self
+    }
+
+    /// Switches to `--list-only`, listing the source files the filters select
+    /// instead of transferring them.
+    pub fn list_only(&mut self) -> &mut Self {
+        self.target = None;
+        self.arg("--list-only")
+    }
+
+    /// All arguments in the order they are passed to rsync.
+    pub fn args(&self) -> Vec<OsString> {
+        let mut args = self.args.clone();
+        args.extend(self.filters().into_iter().map(OsString::from));
+        args.extend(self.sources.iter().cloned());
+        args.extend(self.target.clone());
+        args
+    }
+
+    /// The filter rules in evaluation order: run-level rules, the
+    /// submodule's, then the delete scope's.
+    fn filters(&self) -> Vec<String> {
+        let mut filters = self.run_filters.clone();
+        if self.delete_scope.is_empty() {
+            filters.extend(self.submodule_filters.iter().cloned());
+        } else {
+            filters.extend(self.submodule_filters.iter().map(|rule| sender_only(rule)));
+            for scope in &self.delete_scope {
+                filters.push(format!("--filter=R /{}/***", scope));
+            }
+            filters.push("--filter=P *".to_string());
+        }
+        filters
+    }
+
+    /// Sets an environment variable for the rsync process.
+    pub fn env(&mut self, key: &str, value: &str) -> &mut Self {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// The command as a line that can be pasted into a POSIX shell,
+    /// including its environment variables.
+    pub fn shell_line(&self) -> String {
+        let envs = self
+            .envs
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, shell_quote(value)));
+        let command = std::iter::once(OsString::from("rsync"))
+            .chain(self.args())
+            .map(|arg| shell_quote(&arg.to_string_lossy()));
+        envs.chain(command).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Builds the process to run.
+    pub fn command(&self) -> ProcessCommand {
+        let mut rsync_cmd = ProcessCommand::new("rsync");
+        rsync_cmd.args(self.args());
+        rsync_cmd
+    }
+
+    /// Pairs every argument with a plain-English description of what it does.
+    pub fn explain(&self) -> Vec<(String, String)> {
+        let options = self
+            .args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned());
+        let filters = self.filters();
+        let mut explained: Vec<(String, String)> = options
+            .chain(filters)
+            .map(|arg| {
+                let description = describe_arg(&arg);
+                (arg, description)
+            })
+            .collect();
+        for source in &self.sources {
+            let description = if source.to_string_lossy().ends_with("/./") {
+                "source: everything below the /./ marker is synced into the target root"
+            } else {
+                "source: the trailing slash copies the directory's contents, not the directory itself"
+            };
+            explained.push((
+                source.to_string_lossy().into_owned(),
+                description.to_string(),
+            ));
+        }
+        if let Some(target) = &self.target {
+            explained.push((
+                target.to_string_lossy().into_owned(),
+                "target: the directory the contents are synced into".to_string(),
+            ));
+        }
+        explained
+    }
+}
+
+/// Describes one rsync argument for `--explain`.
+fn describe_arg(arg: &str) -> String {
+    let (flag, value) = match arg.split_once('=') {
+        Some((flag, value)) => (flag, Some(value)),
+        None => (arg, None),
+    };
+    let value = value.unwrap_or_default();
+    match flag {
+        "--recursive" => "recurse into directories".to_string(),
+        "--links" => "copy symlinks as symlinks".to_string(),
+        "--perms" => "preserve permissions".to_string(),
+        "--times" => {
+            "preserve modification times so unchanged files are skipped next time".to_string()
+        }
+        "--group" => "preserve the group".to_string(),
+        "--owner" => "preserve the owner (requires root)".to_string(),
+        "--devices" => "recreate device files (requires root)".to_string(),
+        "--specials" => "recreate special files such as sockets and FIFOs".to_string(),
+        "--delete" => "remove files from the target that no longer exist in the source".to_string(),
+        "--delete-delay" => "apply deletions after the transfer instead of during it".to_string(),
+        "--inplace" => {
+            "write updates directly into target files instead of via a temporary copy".to_string()
+        }
+        "--whole-file" => {
+            "copy changed files whole instead of using the delta algorithm".to_string()
+        }
+        "--no-whole-file" => {
+            "always use the delta algorithm, sending only changed blocks".to_string()
+        }
+        "--itemize-changes" => {
+            "print one summary line per changed file (used to count changes)".to_string()
+        }
+        "--backup" => "keep copies of files that would be overwritten or deleted".to_string(),
+        "--backup-dir" => format!("store those copies in '{}'", value),
+        "--compare-dest" => format!(
+            "skip files that are unchanged in '{}' (references are checked in order)",
+            value
+        ),
+        "--relative" => "recreate source paths below their /./ marker in the target".to_string(),
+        "--out-format" => format!("print one line per changed file in the format '{}'", value),
+        "--remove-source-files" => {
+            "delete source files once they are transferred (--move)".to_string()
+        }
+        "--max-delete" => format!(
+            "delete at most {} files, then stop with exit code 25",
+            value
+        ),
+        "--partial" => "keep partially transferred files so the transfer can resume".to_string(),
+        "--partial-dir" => format!("keep partially transferred files in '{}'", value),
+        "--safe-links" => "ignore symlinks that point outside the source tree".to_string(),
+        "--copy-unsafe-links" => {
+            "copy the referents of symlinks that point outside the source tree".to_string()
+        }
+        "--munge-links" => "store symlinks in a mangled form that cannot be followed".to_string(),
+        "--bwlimit" => format!("limit the transfer rate to {} KiB/s", value),
+        "--timeout" => format!("give up if no data moves for {} seconds", value),
+        "--fake-super" => {
+            "store privileged attributes in xattrs instead of applying them".to_string()
+        }
+        "--dry-run" => "only report what would change; do not modify anything".to_string(),
+        "--list-only" => "list the selected source files instead of transferring them".to_string(),
+        "--include" => format!(
+            "include paths matching '{}' (the first matching rule wins)",
+            value
+        ),
+        "--exclude" => format!(
+            "exclude paths matching '{}' (the first matching rule wins)",
+            value
+        ),
+        "--filter" => match (value.strip_prefix("P "), value.strip_prefix("R ")) {
+            (Some(pattern), _) => {
+                format!("protect target paths matching '{}' from deletion", pattern)
+            }
+            (_, Some(pattern)) => format!("allow deleting target paths matching '{}'", pattern),
+            _ if value.starts_with("+s ") => {
+                format!(
+                    "send paths matching '{}' without exposing them to deletion",
+                    &value[3..]
+                )
+            }
+            _ if value.starts_with("-s ") => {
+                format!(
+                    "skip sending paths matching '{}' without protecting them",
+                    &value[3..]
+                )
+            }
+            _ => format!("filter rule '{}'", value),
+        },
+        _ => "passed through to rsync unchanged".to_string(),
+    }
+}
+
+/// Turns an `--include`/`--exclude` rule into one that only selects what
+/// the sender transfers, leaving deletion on the receiver to other rules.
+pub fn sender_only(rule: &str) -> String {
+    if let Some(pattern) = rule.strip_prefix("--include=") {
+        format!("--filter=+s {}", pattern)
+    } else if let Some(pattern) = rule.strip_prefix("--exclude=") {
+        format!("--filter=-s {}", pattern)
+    } else {
+        rule.to_string()
+    }
+}
+
+/// Quotes `arg` for a POSIX shell, leaving it bare when that is safe.
+pub fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Returns whether `path` is an rsync remote spec (`host:path`,
+/// `user@host:path` or `rsync://...`) rather than a local path. A colon only
+/// counts when it comes before any slash, as rsync itself decides.
+pub fn is_remote(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    if path.starts_with("rsync://") {
+        return true;
+    }
+    match (path.find(':'), path.find('/')) {
+        (Some(colon), Some(slash)) => colon < slash,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+/// Appends a trailing slash so rsync copies the contents of `source` rather
+/// than the directory itself.
+fn source_arg(source: &Path) -> OsString {
+    let mut arg = source.as_os_str().to_os_string();
+    if !arg.to_string_lossy().ends_with('/') {
+        arg.push("/");
+    }
+    arg
+}
+
+/// Normalizes the trailing slash of `target`: exactly one with
+/// `trailing_slash`, none otherwise. A bare `/` or a remote root such as
+/// `host:/` keeps its slash, since `host:` would mean the home directory.
+fn target_arg(target: &Path, trailing_slash: bool) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    let bytes = target.as_os_str().as_bytes();
+    let end = bytes.iter().rposition(|&b| b != b'/').map_or(0, |i| i + 1);
+    if end == 0 {
+        return target.as_os_str().to_os_string();
+    }
+    let end = if bytes[end - 1] == b':' && end < bytes.len() {
+        end + 1
+    } else {
+        end
+    };
+    let trailing_slash = trailing_slash && bytes[end - 1] != b'/';
+    let mut arg = OsString::from(std::ffi::OsStr::from_bytes(&bytes[..end]));
+    if trailing_slash {
+        arg.push("/");
+    }
+    arg
+}
+
+/// Runs `rsync_cmd`, capturing stdout line by line while leaving stderr
+/// attached to the terminal.
+pub fn run_captured(rsync_cmd: &RsyncCommand) -> io::Result<(ExitStatus, Vec<String>)> {
+    let output = rsync_cmd
+        .command()
+        .envs(rsync_cmd.envs.iter().map(|(key, value)| (key, value)))
+        .stderr(Stdio::inherit())
+        .output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok((output.status, stdout.lines().map(str::to_string).collect()))
+}
+
+/// Parses one `--debug=FILTER` line reporting that a path was excluded, such
+/// as `[sender] hiding file a.log because of pattern *.log`, into the path
+/// (with a trailing `/` for directories) and the pattern that excluded it.
+pub fn parse_filter_exclusion(line: &str) -> Option<(String, &str)> {
+    let line = match line.strip_prefix('[') {
+        Some(rest) => rest.split_once("] ")?.1,
+        None => line,
+    };
+    let rest = line.strip_prefix("hiding ")?;
+    let (kind, rest) = rest.split_once(' ')?;
+    let (path, pattern) = rest.rsplit_once(" because of pattern ")?;
+    match kind {
+        "directory" => Some((format!("{}/", path), pattern)),
+        _ => Some((path.to_string(), pattern)),
+    }
+}
+
+/// Kind of change reported by a single `--itemize-changes` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemChange {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// Returns the path of a regular file from one line of `--list-only` output
+/// (`-rw-r--r--  1,234 2024/01/31 12:00:00 lib/main.dart`). Directories,
+/// symlinks and other lines give `None`.
+pub fn parse_list_only_file(line: &str) -> Option<&str> {
+    if !line.starts_with('-') {
+        return None;
+    }
+    let mut rest = line;
+    // Skip the permissions, size, date and time columns.
+    for _ in 0..4 {
+        let field_end = rest.find(char::is_whitespace)?;
+        rest = rest[field_end..].trim_start();
+    }
+    (!rest.is_empty()).then_some(rest)
+}
+
+/// Parses one line of `--itemize-changes` output into the change it describes
+/// and the affected path. Attribute-only updates of directories are ignored
+/// since they do not change any content.
+pub fn parse_itemized(line: &str) -> Option<(ItemChange, &str)> {
+    if let Some(path) = line.strip_prefix("*deleting") {
+        return Some((ItemChange::Deleted, path.trim_start()));
+    }
+
+    let (flags, path) = line.split_once(' ')?;
+    let mut chars = flags.chars();
+    let update_type = chars.next()?;
+    let file_type = chars.next()?;
+    let attrs = chars.as_str();
+    if flags.len() < 11 || !matches!(update_type, '<' | '>' | 'c' | 'h' | '.') {
+        return None;
+    }
+
+    let path = path.trim_start();
+    if attrs.chars().all(|c| c == '+') {
+        Some((ItemChange::Created, path))
+    } else if update_type != '.'
+        || (file_type != 'd' && attrs.chars().any(|c| c != '.' && c != ' '))
+    {
+        Some((ItemChange::Updated, path))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn last_two(rsync_cmd: &RsyncCommand) -> (String, String) {
+        let args = rsync_cmd.args();
+        let n = args.len();
+        (
+            args[n - 2].to_string_lossy().into_owned(),
+            args[n - 1].to_string_lossy().into_owned(),
+        )
+    }
+
+    #[test]
+    fn source_is_always_passed_with_a_trailing_slash() {
+        for source in ["/src/app", "/src/app/"] {
+            let rsync_cmd = RsyncCommand::copy(
+                &ArchiveFlags::default(),
+                Path::new(source),
+                Path::new("/dest"),
+                false,
+            );
+            assert_eq!(last_two(&rsync_cmd).0, "/src/app/");
+        }
+    }
+
+    #[test]
+    fn target_trailing_slash_follows_the_toggle() {
+        let cases = [
+            ("/dest", false, "/dest"),
+            ("/dest/", false, "/dest"),
+            ("/dest//", false, "/dest"),
+            ("/dest", true, "/dest/"),
+            ("/dest/", true, "/dest/"),
+            ("host:/srv/app/", false, "host:/srv/app"),
+            ("host:/srv/app", true, "host:/srv/app/"),
+            ("host:/", false, "host:/"),
+            ("host:/", true, "host:/"),
+            ("/", false, "/"),
+            ("/", true, "/"),
+        ];
+        for (target, trailing_slash, expected) in cases {
+            let mut rsync_cmd = RsyncCommand::copy(
+                &ArchiveFlags::default(),
+                Path::new("/src"),
+                Path::new(target),
+                false,
+            );
+            rsync_cmd.target_trailing_slash(trailing_slash);
+            assert_eq!(
+                last_two(&rsync_cmd).1,
+                expected,
+                "target {:?} with trailing_slash={}",
+                target,
+                trailing_slash
+            );
+        }
+    }
+
+    #[test]
+    fn parse_itemized_classifies_changes() {
+        let cases = [
+            (
+                "*deleting   old/file.txt",
+                Some((ItemChange::Deleted, "old/file.txt")),
+            ),
+            (
+                "*deleting   old dir/",
+                Some((ItemChange::Deleted, "old dir/")),
+            ),
+            (
+                ">f+++++++++ lib/new.dart",
+                Some((ItemChange::Created, "lib/new.dart")),
+            ),
+            (
+                "cd+++++++++ lib/src/",
+                Some((ItemChange::Created, "lib/src/")),
+            ),
+            (
+                ">f+++++++++ docs/release notes.md",
+                Some((ItemChange::Created, "docs/release notes.md")),
+            ),
+            (
+                ">f.st...... lib/main.dart",
+                Some((ItemChange::Updated, "lib/main.dart")),
+            ),
+            (
+                ".f...p..... bin/run",
+                Some((ItemChange::Updated, "bin/run")),
+            ),
+            // A directory whose timestamp alone changed is not a change.
+            (".d..t...... lib/", None),
+            (".f          lib/same.dart", None),
+            ("sending incremental file list", None),
+            ("", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_itemized(line), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn parse_filter_exclusion_reads_hiding_lines() {
+        let exclusion =
+            |line| parse_filter_exclusion(line).map(|(path, pattern)| (path, pattern.to_string()));
+        let owned = |path: &str, pattern: &str| Some((path.to_string(), pattern.to_string()));
+        assert_eq!(
+            exclusion("[sender] hiding file logs/a.log because of pattern *.log"),
+            owned("logs/a.log", "*.log")
+        );
+        assert_eq!(
+            exclusion("[sender] hiding directory build because of pattern build/"),
+            owned("build/", "build/")
+        );
+        assert_eq!(
+            exclusion("hiding file my notes.txt because of pattern *"),
+            owned("my notes.txt", "*")
+        );
+        assert_eq!(
+            exclusion("[sender] showing file lib/main.dart because of pattern lib/***"),
+            None
+        );
+        assert_eq!(exclusion("[sender] hiding file a.log"), None);
+        assert_eq!(exclusion("sending incremental file list"), None);
+    }
+
+    #[test]
+    fn parse_list_only_file_returns_regular_file_paths() {
+        let cases = [
+            (
+                "-rw-r--r--          1,234 2024/01/31 12:00:00 lib/main.dart",
+                Some("lib/main.dart"),
+            ),
+            (
+                "-rwxr-xr-x             42 2024/01/31 12:00:00 bin/run tool",
+                Some("bin/run tool"),
+            ),
+            ("drwxr-xr-x          4,096 2024/01/31 12:00:00 lib", None),
+            (
+                "lrwxrwxrwx              9 2024/01/31 12:00:00 link -> target",
+                None,
+            ),
+            ("-rw-r--r--          1,234 2024/01/31 12:00:00", None),
+            ("", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_list_only_file(line), expected, "{:?}", line);
+        }
+    }
+
+    /// Runs a real rsync for every combination of source and target slash
+    /// and checks where the file lands. Ignored by default since it needs an
+    /// rsync binary; run it with `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "needs rsync installed"]
+    fn files_land_directly_in_the_target_for_every_slash_combination() {
+        check_available().expect("this test runs the real rsync");
+        let scratch =
+            std::env::temp_dir().join(format!("monorepo-agent-slash-{}", std::process::id()));
+        let source = scratch.join("src");
+        fs::create_dir_all(source.join("lib")).unwrap();
+        fs::write(source.join("lib/main.dart"), "void main() {}\n").unwrap();
+
+        for source_slash in [false, true] {
+            for target_slash in [false, true] {
+                let target = scratch.join(format!("dest-{}-{}", source_slash, target_slash));
+                let mut source_path = source.clone().into_os_string();
+                if source_slash {
+                    source_path.push("/");
+                }
+                let mut rsync_cmd = RsyncCommand::copy(
+                    &ArchiveFlags::default(),
+                    &PathBuf::from(source_path),
+                    &target,
+                    false,
+                );
+                rsync_cmd.target_trailing_slash(target_slash);
+                let (status, _) = run_captured(&rsync_cmd).unwrap();
+                assert!(status.success());
+                assert!(
+                    target.join("lib/main.dart").is_file(),
+                    "source slash {}, target slash {}",
+                    source_slash,
+                    target_slash
+                );
+                assert!(!target.join("src").exists());
+            }
+        }
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+}
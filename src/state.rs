@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the tool-managed state file inside the config directory. Unlike
+/// `config.json` it is rewritten by every sync and not meant to be edited.
+pub const STATE_FILE: &str = "state.json";
+
+/// Run state persisted between syncs.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SyncState {
+    /// Start time (seconds since the Unix epoch) of each submodule's last
+    /// successful sync.
+    #[serde(default)]
+    pub last_sync: BTreeMap<String, u64>,
+    /// Target directories created by the tool, the only candidates for
+    /// `--delete-orphan-targets`.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub created_targets: BTreeSet<PathBuf>,
+}
+
+pub fn load_state(config_dir: &Path) -> io::Result<SyncState> {
+    let state_path = config_dir.join(STATE_FILE);
+    if !state_path.exists() {
+        return Ok(SyncState::default());
+    }
+    let file = fs::File::open(&state_path)?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to parse {}: {}", state_path.display(), e),
+        )
+    })
+}
+
+pub fn save_state(config_dir: &Path, state: &SyncState) -> io::Result<()> {
+    let contents = serde_json::to_string_pretty(state)?;
+    fs::write(config_dir.join(STATE_FILE), contents + "\n")
+}
+
+/// Current time in seconds since the Unix epoch.
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Returns whether any file or directory under `dir` was modified at or after
+/// `since` (seconds since the Unix epoch). Directory mtimes are included so
+/// deletions and renames count as changes.
+pub fn modified_since(dir: &Path, since: u64) -> io::Result<bool> {
+    let since = UNIX_EPOCH + Duration::from_secs(since);
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        if fs::metadata(&dir)?.modified()? >= since {
+            return Ok(true);
+        }
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else if metadata.modified()? >= since {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
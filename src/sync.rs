@@ -0,0 +1,3779 @@
+use crate::audit::{self, AuditEntry, AuditRecord};
+use crate::config::{self, AppConfig, ArchiveFlags, SubmoduleConfig};
+use crate::display;
+use crate::history;
+use crate::prompt::{self, Choice};
+use crate::rsync::{self, ItemChange, RsyncCommand};
+use crate::state::{self, SyncState};
+use crate::verify;
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command as ProcessCommand, ExitStatus, Stdio};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Files modified up to this many seconds before a submodule's last sync are
+/// still treated as new by `--since-last-sync`.
+const CLOCK_SKEW_MARGIN_SECS: u64 = 60;
+
+/// Options controlling a single `sync` run, parsed from the `sync` command
+/// line.
+#[derive(Args, Clone, Debug, Default)]
+pub struct SyncOptions {
+    /// Never delete from targets; abort if a deletion would be needed
+    #[arg(long)]
+    pub abort_on_delete: bool,
+    /// List the files selected by each submodule's filters without syncing
+    #[arg(long)]
+    pub list_files: bool,
+    /// Describe every argument of each submodule's rsync command without syncing
+    #[arg(long)]
+    pub explain: bool,
+    /// Report which filter rule excluded each skipped file, per submodule,
+    /// from rsync's --debug=FILTER output, without syncing
+    #[arg(long)]
+    pub debug_filters: bool,
+    /// Stream one JSON object per line to stdout as each submodule starts
+    /// and completes, instead of the usual messages
+    #[arg(
+        long,
+        conflicts_with_all = ["list_files", "explain", "debug_filters", "dry_run", "quiet_dry_run", "out_format", "profile"]
+    )]
+    pub json_lines: bool,
+    /// Report what each submodule would change, one itemized line per file,
+    /// without modifying anything
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+    /// Like --dry-run, but only print how many changes each submodule has
+    /// pending instead of listing them
+    #[arg(long, conflicts_with = "context")]
+    pub quiet_dry_run: bool,
+    /// With --dry-run, list what each changed target directory already
+    /// contains, to review changes in context
+    #[arg(long, requires = "dry_run")]
+    pub context: bool,
+    /// Recursively chown synced targets to this user (requires root)
+    #[arg(long)]
+    pub target_owner: Option<String>,
+    /// Recursively chown synced targets to this group (requires root)
+    #[arg(long)]
+    pub target_group: Option<String>,
+    /// Move files: delete them from the source once transferred
+    #[arg(long = "move")]
+    pub move_files: bool,
+    /// Empty each existing local target (keeping the directory itself)
+    /// right before its rsync, for a clean deploy. Asks for confirmation
+    /// unless --yes is given; submodules with `delete: false` are left alone
+    #[arg(long, conflicts_with = "abort_on_delete")]
+    pub pre_clean_target: bool,
+    /// Ask before creating each missing local target, showing its full path:
+    /// yes, no (skip the submodule), all (create the rest without asking)
+    /// or abort. Without a terminal the run aborts unless --yes is given
+    #[arg(long, conflicts_with = "assume_target_clean")]
+    pub confirm_target_creation_per_submodule: bool,
+    /// When syncing all submodules, skip those whose local target does not
+    /// exist yet instead of creating it, so only existing deploys are
+    /// updated. Submodules named with --submodules are still created
+    #[arg(long, conflicts_with = "on_missing_target")]
+    pub target_existing_required: bool,
+    /// What to do with a submodule whose local target does not exist:
+    /// create it, skip the submodule with a note, or fail the run
+    #[arg(long, value_enum, default_value_t)]
+    pub on_missing_target: OnMissingTarget,
+    /// Create a .sync-in-progress marker in each local target while its
+    /// rsync runs, so services reading the target can pause. It is removed
+    /// afterwards, even when the sync fails, but left behind if the process
+    /// is killed. An existing marker aborts the run
+    #[arg(long)]
+    pub target_lock: bool,
+    /// Answer yes to confirmation prompts, such as the one before a sync
+    /// that would delete files from a target; required when stdin is not a
+    /// terminal
+    #[arg(short, long)]
+    pub yes: bool,
+    /// Exclude files with this extension from every submodule (repeatable)
+    #[arg(long = "exclude-ext", value_name = "EXT", value_parser = parse_extension)]
+    pub exclude_ext: Vec<String>,
+    /// Exclude common build and dependency directories (node_modules,
+    /// target, build, ...) from every submodule, plus the config's
+    /// `build_artifacts` patterns
+    #[arg(long)]
+    pub exclude_build_artifacts: bool,
+    /// Print the patterns --exclude-build-artifacts excludes, without syncing
+    #[arg(long)]
+    pub show_preset: bool,
+    /// Only sync N directory levels deep: 0 keeps only files at the source
+    /// root, 1 also files one directory down, and so on. Implemented as a
+    /// single rule excluding the directories at level N+1, e.g.
+    /// --exclude=/*/*/ for --max-depth 1, evaluated before all other rules
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<u32>,
+    /// Read newline-separated exclude patterns from stdin and apply them to
+    /// every selected submodule, in addition to the configured excludes.
+    /// Blank lines and lines starting with # are ignored. Submodules are
+    /// still selected with --submodules; since stdin is not a terminal,
+    /// confirmations need --yes
+    #[arg(long)]
+    pub exclude_from_stdin: bool,
+    /// Pass each target to rsync with a trailing slash, so rsync always
+    /// treats it as a directory. Either way the source's contents land
+    /// directly in the target, since the source is passed as `source/`
+    #[arg(long)]
+    pub target_trailing_slash: bool,
+    /// Use this include pattern instead of each submodule's configured ones
+    /// for this run only (repeatable); the config is not modified
+    #[arg(long, value_name = "PATTERN", alias = "include-glob")]
+    pub include: Vec<String>,
+    /// Use this exclude pattern instead of each submodule's configured ones
+    /// for this run only (repeatable); the config is not modified
+    #[arg(long, value_name = "PATTERN", alias = "exclude-glob")]
+    pub exclude: Vec<String>,
+    /// Add the --include and --exclude patterns to the configured ones
+    /// instead of replacing them
+    #[arg(long)]
+    pub append_patterns: bool,
+    /// Patterns read by `--exclude-from-stdin`.
+    #[arg(skip)]
+    pub stdin_excludes: Vec<String>,
+    /// Set from the global `--quiet` and `--verbose` flags.
+    #[arg(skip)]
+    pub verbosity: Verbosity,
+    /// Only sync submodules whose sources changed since their last successful sync
+    #[arg(long)]
+    pub since_last_sync: bool,
+    /// Remove target directories created for submodules no longer in the config
+    #[arg(long)]
+    pub delete_orphan_targets: bool,
+    /// Write a timestamped audit record of planned changes and outcomes to this directory
+    #[arg(long, value_name = "DIR")]
+    pub audit_dir: Option<PathBuf>,
+    /// Collect the run's reports in this directory, created if missing:
+    /// summary.json, audit records under audit/ (unless --audit-dir is
+    /// given) and rsync/<submodule>.log with each rsync command and its output
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+    /// Also write the run summary as JSON to this file, alongside the usual
+    /// console output. It is written even when submodules failed or the run
+    /// stopped early
+    #[arg(long, value_name = "FILE")]
+    pub summary_json_file: Option<PathBuf>,
+    /// Do not auto-exclude the .monorepo directory from sources that contain it
+    #[arg(long)]
+    pub no_config_dir_exclude: bool,
+    /// Protect target files matching this pattern from --delete (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub preserve_target: Vec<String>,
+    /// Only delete target files under this path (relative to the target, may
+    /// be a glob; repeatable). Files elsewhere are still copied but never
+    /// deleted, and excluded files inside it stay protected
+    #[arg(
+        long,
+        alias = "target-glob-delete-scope",
+        value_name = "PATH",
+        value_parser = parse_delete_scope
+    )]
+    pub delete_scope: Vec<String>,
+    /// Canonicalize submodule source paths so symlinked sources resolve to their real location
+    #[arg(long)]
+    pub resolve_symlinks_in_config: bool,
+    /// Print a timing breakdown of the run when it finishes
+    #[arg(long)]
+    pub profile: bool,
+    /// Stop deleting after N files per submodule (rsync --max-delete)
+    #[arg(long, value_name = "N")]
+    pub max_delete: Option<u32>,
+    /// Delete from targets only after all files were transferred (rsync
+    /// --delete-delay), so a live target never misses a file that is just
+    /// being replaced. rsync keeps the list of pending deletions in memory
+    /// until the end, which grows with the number of files deleted
+    #[arg(long)]
+    pub delete_delay: bool,
+    /// Skip checking that each target exists and creating missing ones, and
+    /// pass straight to rsync, which fails if a target is unusable. For
+    /// repeated runs on a known-good layout
+    #[arg(long)]
+    pub assume_target_clean: bool,
+    /// Copy whole files instead of using rsync's delta algorithm. This is the
+    /// default when the target is a local path, since deltas only save time
+    /// over a network
+    #[arg(short = 'W', long)]
+    pub whole_file: bool,
+    /// Always use rsync's delta algorithm, even for local targets
+    #[arg(long, conflicts_with = "whole_file")]
+    pub no_whole_file: bool,
+    /// Skip symlinks that point outside the source tree (recommended for
+    /// untrusted sources)
+    #[arg(long, help_heading = "Symlink safety")]
+    pub safe_links: bool,
+    /// Copy the files that outside-pointing symlinks refer to instead of the
+    /// links themselves; symlinks within the tree are kept
+    #[arg(long, help_heading = "Symlink safety", conflicts_with = "safe_links")]
+    pub copy_unsafe_links: bool,
+    /// Store symlinks in the target in a mangled form that cannot be followed
+    /// until they are synced back with --munge-links
+    #[arg(long, help_heading = "Symlink safety")]
+    pub munge_links: bool,
+    /// Store ownership, permissions, devices and special files in the
+    /// user.rsync.%stat xattr on the target instead of applying them, so a
+    /// backup in a user-owned location can restore them later. Requires
+    /// xattr support on the target filesystem; set archive_flags.owner,
+    /// group and perms in the config for there to be anything to store
+    #[arg(long)]
+    pub fake_super: bool,
+    /// Keep partially transferred files so an interrupted sync can resume
+    #[arg(long)]
+    pub partial: bool,
+    /// Keep partial files in this directory instead of beside their final
+    /// names. A relative path is created inside each target directory and
+    /// protected from --delete
+    #[arg(long, value_name = "DIR", requires = "partial", value_parser = parse_partial_dir)]
+    pub partial_dir: Option<PathBuf>,
+    /// Sync into a path built from this template instead of each
+    /// submodule's target, relative to the monorepo root. Placeholders: {submodule}
+    /// (submodule name), {date} (YYYY-MM-DD), {time} (HHMMSS) and {timestamp}
+    /// (YYYYMMDDTHHMMSS), all taken from the local start time of the run.
+    /// Example: ../releases/{date}/{submodule}
+    #[arg(long, value_name = "TEMPLATE", value_parser = TargetTemplate::parse)]
+    pub target_template: Option<TargetTemplate>,
+    /// Point a `current` symlink at the newest synced release. It is placed
+    /// beside the first template component with a date or time placeholder
+    #[arg(long, requires = "target_template")]
+    pub current_link: bool,
+    /// Allow local targets outside the target base: the config's
+    /// `target_base`, or the monorepo's parent directory without one. By
+    /// default a target that resolves outside it (e.g. through `..`) is an
+    /// error
+    #[arg(long)]
+    pub allow_target_outside_parent: bool,
+    /// Limit each rsync to RATE bytes per second (e.g. 500K, 2M)
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub bwlimit: Option<u64>,
+    /// Cap the combined rate of all rsyncs at RATE bytes per second. With
+    /// --jobs, each starting rsync gets an equal share of the budget not
+    /// held by running ones and keeps that rate until it finishes, then
+    /// returns its share
+    #[arg(
+        long,
+        alias = "max-concurrent-bytes",
+        value_name = "RATE",
+        value_parser = parse_size
+    )]
+    pub total_bwlimit: Option<u64>,
+    /// Report submodules that were already up to date separately from those
+    /// whose targets changed, and list both groups after the run
+    #[arg(long)]
+    pub report_unchanged: bool,
+    /// Stop a submodule's rsync when no data has moved for SECONDS (rsync
+    /// --timeout), to catch hangs. A submodule's `timeout_secs` overrides it
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: Option<u64>,
+    /// Dry-run every submodule first, print how many bytes each would
+    /// transfer (with an ETA under --bwlimit) and ask before syncing
+    #[arg(long)]
+    pub estimate: bool,
+    /// Skip a submodule unless its target filesystem keeps at least SIZE free
+    /// after the transfer (e.g. 500M, 2G; estimated with a dry-run)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_free_space: Option<u64>,
+    /// Abort the whole run instead of skipping when --min-free-space is not met
+    #[arg(long, requires = "min_free_space")]
+    pub abort_on_low_space: bool,
+    /// Skip submodules whose target is on a network filesystem (NFS, SMB,
+    /// ...) or on a remote host. Detection needs Linux; elsewhere every
+    /// local path counts as local
+    #[arg(long)]
+    pub local_only: bool,
+    /// Only sync submodules whose `meta.tags` include this tag (repeatable;
+    /// any listed tag matches)
+    #[arg(long, value_name = "TAG")]
+    pub tag: Vec<String>,
+    /// How --tag combines with --submodules: sync submodules matching both,
+    /// or matching either
+    #[arg(long, value_enum, default_value_t = TagCombine::Intersection, requires = "tag")]
+    pub tag_combine: TagCombine,
+    /// Print each changed file using this rsync --out-format string instead
+    /// of the itemized format; every line printed counts as one change
+    #[arg(long, value_name = "FORMAT")]
+    pub out_format: Option<String>,
+    /// Ask for confirmation (or --yes) before a sync that would delete more
+    /// than this percentage of a target's existing files
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    pub confirm_delete_threshold: Option<f64>,
+    /// Before syncing, check that rsync is installed, sources exist, targets
+    /// are writable, no paths overlap and no target loses more than the
+    /// --confirm-delete-threshold (default 50%), then print GO or NO-GO.
+    /// Nothing is synced on NO-GO
+    #[arg(long)]
+    pub preflight: bool,
+    /// Sync even when --preflight reports NO-GO
+    #[arg(long, requires = "preflight")]
+    pub force: bool,
+    /// Keep target files that a sync overwrites or deletes in DIR/<submodule>,
+    /// so they can be put back with `restore`. Only the latest version of
+    /// each file is kept
+    #[arg(long, value_name = "DIR", value_parser = parse_backup_dir)]
+    pub backup_dir: Option<PathBuf>,
+    /// When a submodule's `validate_command` fails, copy its --backup-dir
+    /// files back over the target, as `restore` does. Files the sync added
+    /// are not removed
+    #[arg(long, requires = "backup_dir")]
+    pub rollback_on_validation_failure: bool,
+    /// Skip transferring files that are unchanged in DIR/<submodule>
+    /// (repeatable; rsync checks the directories in the order given). Each
+    /// reference directory must exist for local targets
+    #[arg(long, value_name = "DIR", value_parser = parse_backup_dir)]
+    pub compare_dest: Vec<PathBuf>,
+    /// Remove N leading directories from synced paths, e.g. 1 publishes
+    /// dist/* as the target root. Each directory N levels deep becomes an
+    /// rsync source with --relative and a /./ marker. Limits: files less than
+    /// N levels deep are not synced, include/exclude patterns match the
+    /// stripped paths, and same-named files from different directories
+    /// overwrite each other
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub strip_components: Option<u32>,
+    /// Only sync the files selected by each submodule's filters for which
+    /// this shell command, run in the source with the file's path as its
+    /// argument, exits 0 (e.g. "grep -q @deploy"). Files it rejects are
+    /// neither synced nor deleted from the target
+    #[arg(long, value_name = "CMD", conflicts_with = "strip_components")]
+    pub content_filter: Option<String>,
+    /// Re-run a submodule once when rsync reports vanished source files (exit
+    /// code 24), and only warn if the retry hits it again
+    #[arg(long)]
+    pub retry_on_vanished: bool,
+    /// Experimental: sync each submodule's top-level directories with N
+    /// concurrent rsync processes, none of which delete, then run one final
+    /// full pass that owns --delete and catches anything left. The final pass
+    /// still walks the whole tree, and --max-delete only applies to it
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(2..),
+        conflicts_with = "strip_components"
+    )]
+    pub parallel_within_submodule: Option<u32>,
+    /// Set an environment variable for every rsync process, e.g. RSYNC_RSH
+    /// or RSYNC_PASSWORD (repeatable)
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+    pub rsync_env: Vec<(String, String)>,
+    /// Sync up to N submodules at the same time (default 1). Each
+    /// submodule's output is printed as one block when its transfer
+    /// finishes, and the results are reported in config order at the end.
+    /// With --dry-run, up to N dry-runs run at once and the preview is
+    /// printed per submodule in config order once all have finished
+    #[arg(
+        short,
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub jobs: Option<usize>,
+    /// With --jobs, group submodules by the filesystem their target is on
+    /// (its device id, or that of the nearest existing parent for targets
+    /// not created yet) and sync each group one submodule at a time, running
+    /// only different groups in parallel. Targets whose filesystem cannot be
+    /// determined, such as remote ones, each form a group of their own
+    #[arg(long, requires = "jobs")]
+    pub group_by_target_fs: bool,
+    /// Permissions, in octal, for target directories the sync creates, e.g.
+    /// 750. Existing targets and rsync's handling of copied files are unaffected
+    #[arg(
+        long,
+        alias = "create-target-as",
+        value_name = "OCTAL",
+        value_parser = parse_mode
+    )]
+    pub target_dir_mode: Option<u32>,
+}
+
+/// Marker file `--target-lock` keeps in a target while it is being synced.
+pub const TARGET_LOCK_FILE: &str = ".sync-in-progress";
+
+/// A `--target-lock` marker, removed when dropped so a failed sync does not
+/// leave the target locked.
+struct TargetLock(PathBuf);
+
+impl TargetLock {
+    /// Creates the marker in `target`, recording this process and the start
+    /// time. Fails if another sync's marker is already there.
+    fn acquire(target: &Path) -> io::Result<Self> {
+        let path = target.join(TARGET_LOCK_FILE);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => io::Error::new(
+                    e.kind(),
+                    format!(
+                        "{:?} is locked by another sync; remove {} if it is stale",
+                        display::path(target),
+                        TARGET_LOCK_FILE
+                    ),
+                ),
+                _ => e,
+            })?;
+        writeln!(file, "pid {}", std::process::id())?;
+        writeln!(file, "started {}", chrono::Local::now().to_rfc3339())?;
+        Ok(TargetLock(path))
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Directories `--exclude-build-artifacts` excludes wherever they occur.
+pub const BUILD_ARTIFACTS: [&str; 9] = [
+    "node_modules/",
+    "target/",
+    "build/",
+    "dist/",
+    ".dart_tool/",
+    "__pycache__/",
+    ".gradle/",
+    ".next/",
+    ".venv/",
+];
+
+/// Parses an octal permission mode such as `755` or `0750`.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "'{}' is not an octal mode between 0 and 7777",
+            value
+        )),
+    }
+}
+
+/// Parses a `KEY=VALUE` environment assignment. The key must be a valid
+/// shell variable name, since `show-command` prints it as a prefix.
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    let Some((key, val)) = value.split_once('=') else {
+        return Err(format!("'{}' is not in KEY=VALUE form", value));
+    };
+    let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!(
+            "'{}' is not a valid environment variable name",
+            key
+        ));
+    }
+    Ok((key.to_string(), val.to_string()))
+}
+
+/// Makes a `--backup-dir` absolute, so rsync does not resolve it relative to
+/// each target.
+pub fn parse_backup_dir(value: &str) -> Result<PathBuf, String> {
+    std::path::absolute(value).map_err(|e| format!("invalid backup dir '{}': {}", value, e))
+}
+
+/// The directory a submodule's backups go to inside a `--backup-dir`.
+pub fn backup_path(backup_dir: &Path, submodule: &str) -> PathBuf {
+    backup_dir.join(submodule)
+}
+
+/// Parses a percentage between 0 and 100.
+fn parse_percent(value: &str) -> Result<f64, String> {
+    match value.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("'{}' is not a percentage between 0 and 100", value)),
+    }
+}
+
+/// What `--on-missing-target` does with a missing local target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnMissingTarget {
+    #[default]
+    Create,
+    Skip,
+    Error,
+}
+
+/// How `--tag` and `--submodules` selections combine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TagCombine {
+    #[default]
+    Intersection,
+    Union,
+}
+
+/// Parses a byte size with an optional binary suffix: `K`, `M`, `G` or `T`,
+/// optionally followed by `B` or `iB` (`512`, `500M`, `2GiB`).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let number = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (digits, shift) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 10),
+        Some('M') => (&number[..number.len() - 1], 20),
+        Some('G') => (&number[..number.len() - 1], 30),
+        Some('T') => (&number[..number.len() - 1], 40),
+        _ => (number, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{}'", value))
+}
+
+/// Formats a byte count with a binary unit for messages.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// A `--target-template` path with `{placeholder}` components.
+#[derive(Clone, Debug)]
+pub struct TargetTemplate(String);
+
+impl TargetTemplate {
+    const PLACEHOLDERS: [&'static str; 4] = ["submodule", "date", "time", "timestamp"];
+
+    fn parse(value: &str) -> Result<Self, String> {
+        if value.is_empty() {
+            return Err("target template must not be empty".to_string());
+        }
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                return Err(format!("unclosed '{{' in target template '{}'", value));
+            };
+            let name = &rest[start + 1..start + len];
+            if !Self::PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown placeholder '{{{}}}'; expected one of {{{}}}",
+                    name,
+                    Self::PLACEHOLDERS.join("}, {")
+                ));
+            }
+            rest = &rest[start + len + 1..];
+        }
+        Ok(TargetTemplate(value.to_string()))
+    }
+
+    fn uses(&self, placeholder: &str) -> bool {
+        self.0.contains(&format!("{{{}}}", placeholder))
+    }
+
+    fn expand_str(text: &str, submodule: &str, stamp: &chrono::DateTime<chrono::Local>) -> String {
+        text.replace("{submodule}", submodule)
+            .replace("{date}", &stamp.format("%Y-%m-%d").to_string())
+            .replace("{timestamp}", &stamp.format("%Y%m%dT%H%M%S").to_string())
+            .replace("{time}", &stamp.format("%H%M%S").to_string())
+    }
+
+    /// The target path for `submodule`, relative to the monorepo root unless
+    /// the template is absolute.
+    fn expand(&self, submodule: &str, stamp: &chrono::DateTime<chrono::Local>) -> PathBuf {
+        PathBuf::from(Self::expand_str(&self.0, submodule, stamp))
+    }
+
+    /// The `current` symlink path and the name it should point to: the link
+    /// sits beside the first component that depends on the run time.
+    fn current_link(
+        &self,
+        submodule: &str,
+        stamp: &chrono::DateTime<chrono::Local>,
+    ) -> Option<(PathBuf, PathBuf)> {
+        let components: Vec<&str> = self.0.split('/').collect();
+        let index = components.iter().position(|c| {
+            ["{date}", "{time}", "{timestamp}"]
+                .iter()
+                .any(|p| c.contains(p))
+        })?;
+        let parent = Self::expand_str(&components[..index].join("/"), submodule, stamp);
+        let release = Self::expand_str(components[index], submodule, stamp);
+        Some((
+            PathBuf::from(parent).join("current"),
+            PathBuf::from(release),
+        ))
+    }
+}
+
+/// Reads exclude patterns for `--exclude-from-stdin`, one per line.
+pub fn read_stdin_excludes() -> io::Result<Vec<String>> {
+    let mut patterns = Vec::new();
+    for line in io::stdin().lines() {
+        let line = line?;
+        let pattern = line.trim();
+        if !pattern.is_empty() && !pattern.starts_with('#') {
+            patterns.push(pattern.to_string());
+        }
+    }
+    Ok(patterns)
+}
+
+/// Normalizes a `--delete-scope` path by trimming surrounding slashes.
+fn parse_delete_scope(value: &str) -> Result<String, String> {
+    let scope = value.trim_matches('/');
+    if scope.is_empty() || scope.split('/').any(|c| c == "..") {
+        return Err(format!("invalid delete scope '{}'", value));
+    }
+    Ok(scope.to_string())
+}
+
+/// Normalizes an `--exclude-ext` value, accepting `map`, `.map` or `*.map`.
+fn parse_extension(value: &str) -> Result<String, String> {
+    let ext = value.trim_start_matches('*').trim_start_matches('.');
+    if ext.is_empty() || ext.contains('/') {
+        return Err(format!("invalid file extension '{}'", value));
+    }
+    Ok(ext.to_string())
+}
+
+/// How much a command prints besides errors and warnings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors and warnings.
+    Quiet,
+    /// One line per submodule.
+    #[default]
+    Normal,
+    /// Also the rsync commands and their output, with rsync's `-v`.
+    Verbose,
+}
+
+/// Outcome counts of a `sync` run.
+#[derive(Serialize, Debug, Default)]
+pub struct SyncSummary {
+    /// Submodules selected for the run, before any were skipped.
+    pub selected: usize,
+    /// Submodules that synced successfully.
+    pub synced: usize,
+    /// Submodules whose source was missing or whose rsync failed.
+    pub failed: usize,
+    /// Submodules whose targets were actually modified.
+    pub changed: usize,
+    /// Submodules skipped because nothing needed syncing.
+    pub skipped: usize,
+    /// Submodules stopped by the `--max-delete` limit.
+    pub delete_limited: usize,
+    /// Per-submodule results, in the order they were processed.
+    pub results: Vec<SyncResult>,
+    /// Emit a `--json-lines` event for every recorded result.
+    #[serde(skip)]
+    json_lines: bool,
+}
+
+impl SyncSummary {
+    /// Counts a failed submodule: stops by `--max-delete` separately from
+    /// other failures.
+    pub fn count_failure(&mut self, outcome: SyncOutcome) {
+        match outcome {
+            SyncOutcome::DeleteLimitExceeded => self.delete_limited += 1,
+            _ => self.failed += 1,
+        }
+    }
+
+    fn record(
+        &mut self,
+        plan: &SyncPlan,
+        outcome: SyncOutcome,
+        exit_code: Option<i32>,
+        changes: usize,
+        elapsed: Duration,
+    ) {
+        let result = SyncResult {
+            name: plan.submodule.name.clone(),
+            source: plan.source_path.clone(),
+            target: plan.target_path.clone(),
+            outcome,
+            exit_code,
+            changes,
+            elapsed,
+        };
+        if self.json_lines {
+            emit_event(&Event::Complete(&result));
+        }
+        self.results.push(result);
+    }
+}
+
+/// The outcome of an rsync run that failed with exit `code`.
+pub fn failure_outcome(code: Option<i32>) -> SyncOutcome {
+    match code {
+        Some(rsync::EXIT_TIMEOUT) => SyncOutcome::TimedOut,
+        Some(rsync::EXIT_MAX_DELETE) => SyncOutcome::DeleteLimitExceeded,
+        _ => SyncOutcome::Failed,
+    }
+}
+
+/// One line of `--json-lines` output.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    /// A submodule's transfer is starting.
+    Start {
+        name: &'a str,
+        source: &'a Path,
+        target: &'a Path,
+    },
+    /// A submodule finished, was skipped or failed.
+    Complete(&'a SyncResult),
+    /// `--delete-orphan-targets` removed a target no submodule uses anymore.
+    OrphanRemoved { target: &'a Path },
+}
+
+/// Writes `event` as one line of JSON to stdout and flushes it right away, so
+/// consumers see progress as it happens.
+fn emit_event(event: &Event) {
+    let line = serde_json::to_string(event).expect("events serialize to JSON");
+    let mut stdout = io::stdout().lock();
+    // Like println!, a closed stdout is not worth failing the sync over.
+    let _ = writeln!(stdout, "{}", line).and_then(|()| stdout.flush());
+}
+
+/// What happened to one submodule during a run.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncOutcome {
+    Synced,
+    /// Dry-run only; nothing was modified.
+    DryRun,
+    /// Skipped by `--since-last-sync` because the source did not change.
+    Unchanged,
+    /// Skipped by `--local-only` because the target is not local.
+    NotLocal,
+    /// Skipped because creating its missing target was declined.
+    TargetDeclined,
+    /// Skipped by `--on-missing-target skip` or `--target-existing-required`
+    /// because the target does not exist.
+    TargetMissing,
+    SourceMissing,
+    InsufficientSpace,
+    ChownFailed,
+    /// The `pre_sync`, `post_sync` or `cleanup_command` hook exited with an
+    /// error.
+    HookFailed,
+    /// The `validate_command` exited with an error.
+    ValidationFailed,
+    /// rsync gave up after no data moved for the timeout period.
+    TimedOut,
+    DeleteLimitExceeded,
+    Failed,
+}
+
+impl SyncOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SyncOutcome::Synced => "synced",
+            SyncOutcome::DryRun => "dry run",
+            SyncOutcome::Unchanged => "unchanged",
+            SyncOutcome::NotLocal => "not local",
+            SyncOutcome::TargetDeclined => "target declined",
+            SyncOutcome::TargetMissing => "target missing",
+            SyncOutcome::SourceMissing => "source missing",
+            SyncOutcome::InsufficientSpace => "insufficient space",
+            SyncOutcome::ChownFailed => "chown failed",
+            SyncOutcome::HookFailed => "hook failed",
+            SyncOutcome::ValidationFailed => "validation failed",
+            SyncOutcome::TimedOut => "timed out",
+            SyncOutcome::DeleteLimitExceeded => "deletion limit exceeded",
+            SyncOutcome::Failed => "failed",
+        }
+    }
+
+    pub fn is_failure(self) -> bool {
+        !matches!(
+            self,
+            SyncOutcome::Synced
+                | SyncOutcome::DryRun
+                | SyncOutcome::Unchanged
+                | SyncOutcome::NotLocal
+                | SyncOutcome::TargetDeclined
+                | SyncOutcome::TargetMissing
+        )
+    }
+}
+
+/// The result of processing one submodule.
+#[derive(Serialize, Debug, Clone)]
+pub struct SyncResult {
+    pub name: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub outcome: SyncOutcome,
+    /// Exit code of the last rsync run, if rsync ran.
+    pub exit_code: Option<i32>,
+    /// Number of changed files rsync reported.
+    pub changes: usize,
+    pub elapsed: Duration,
+}
+
+/// Validates a `--partial-dir` value: it must not climb out of the target with
+/// `..`, and an absolute directory must already exist.
+fn parse_partial_dir(value: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(value);
+    if value.is_empty() {
+        return Err("partial dir must not be empty".to_string());
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("partial dir '{}' must not contain '..'", value));
+    }
+    if path.is_absolute() && !path.is_dir() {
+        return Err(format!("partial dir '{}' does not exist", value));
+    }
+    Ok(path)
+}
+
+/// A submodule resolved to concrete source and target paths.
+pub struct SyncPlan<'a> {
+    pub submodule: &'a SubmoduleConfig,
+    pub archive: &'a ArchiveFlags,
+    /// The config's `global_include` and `global_exclude` patterns.
+    pub global_include: &'a [String],
+    pub global_exclude: &'a [String],
+    /// The config's additions to the `--exclude-build-artifacts` preset.
+    pub build_artifacts: &'a [String],
+    pub source_path: PathBuf,
+    pub target_path: PathBuf,
+    /// Set while the target comes from a `target_command` that has not run
+    /// yet; `target_path` then only shows the command.
+    pub target_unresolved: bool,
+    /// Anchored rsync pattern for the monorepo's `.monorepo` directory when
+    /// it lies inside this submodule's source.
+    pub config_dir_pattern: Option<String>,
+    /// Source subdirectories whose contents are synced into the target root
+    /// by `--strip-components`; empty when paths are kept as they are.
+    pub strip_roots: Vec<String>,
+    /// Rules from the `.monorepo-sync` file at the source root, if any.
+    pub source_rules: Option<config::SourceRules>,
+    /// Files accepted by `--content-filter`, relative to the source; when
+    /// set, nothing else is synced.
+    pub content_matches: Option<Vec<String>>,
+}
+
+/// Resolves the submodules to process. `selected` restricts the run to the
+/// given names; unknown names are reported and skipped.
+pub fn plan_submodules<'a>(
+    root: &Path,
+    app_config: &'a AppConfig,
+    selected: Option<&[String]>,
+) -> io::Result<Vec<SyncPlan<'a>>> {
+    let submodules = &app_config.submodules;
+    let target_base = app_config.target_base(root)?;
+
+    let submodules_to_process: Vec<&SubmoduleConfig> = match selected {
+        Some(names) => {
+            for name in names {
+                if !submodules.iter().any(|s| &s.name == name) {
+                    eprintln!("Submodule '{}' not found in config, skipping.", name);
+                }
+            }
+            submodules
+                .iter()
+                .filter(|s| names.contains(&s.name))
+                .collect()
+        }
+        None => submodules.iter().collect(),
+    };
+
+    let config_dir = root.join(config::CONFIG_DIR);
+    let mut plans = Vec::new();
+    for submodule in submodules_to_process {
+        let source_path = app_config.source_path(root, submodule);
+        if let Some(subdir) = &submodule.target_subdir {
+            config::validate_subdir(subdir).map_err(|reason| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Submodule '{}': {}", submodule.name, reason),
+                )
+            })?;
+        }
+        // A target_command only runs once a sync resolves it; until then
+        // the plan shows the command itself.
+        let target_path = match (&submodule.target, &submodule.target_command) {
+            (Some(_), Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Submodule '{}' sets both target and target_command",
+                        submodule.name
+                    ),
+                ));
+            }
+            (None, Some(command)) => PathBuf::from(format!("$({})", command)),
+            (target, None) => target_path(root, &target_base, submodule, target.clone()),
+        };
+        plans.push(SyncPlan {
+            target_unresolved: submodule.target_command.is_some(),
+            submodule,
+            archive: &app_config.archive_flags,
+            global_include: &app_config.global_include,
+            global_exclude: &app_config.global_exclude,
+            build_artifacts: &app_config.build_artifacts,
+            config_dir_pattern: contained_pattern(&source_path, &config_dir),
+            strip_roots: Vec::new(),
+            content_matches: None,
+            source_rules: config::load_source_rules(&source_path)?,
+            source_path,
+            target_path,
+        });
+    }
+    Ok(plans)
+}
+
+/// Where `submodule` syncs to: `target` resolved against `root` unless it is
+/// remote, or its default directory in `target_base` without one.
+fn target_path(
+    root: &Path,
+    target_base: &Path,
+    submodule: &SubmoduleConfig,
+    target: Option<PathBuf>,
+) -> PathBuf {
+    submodule.in_subdir(match target {
+        Some(target) if rsync::is_remote(&target) => target,
+        Some(target) => root.join(target),
+        None => target_base.join(&submodule.name),
+    })
+}
+
+/// Runs the `target_command` of every plan whose target is still unresolved
+/// and points the plan at the path it printed. Targets resolved earlier in
+/// the run are taken from `resolved`, so each command runs at most once.
+pub fn resolve_target_commands(
+    plans: &mut [SyncPlan],
+    root: &Path,
+    target_base: &Path,
+    resolved: &mut HashMap<String, PathBuf>,
+) -> io::Result<()> {
+    for plan in plans.iter_mut().filter(|plan| plan.target_unresolved) {
+        let name = &plan.submodule.name;
+        if !resolved.contains_key(name) {
+            let command = plan.submodule.target_command.as_deref().unwrap_or_default();
+            let target = run_target_command(root, name, command)?;
+            resolved.insert(
+                name.clone(),
+                target_path(root, target_base, plan.submodule, Some(target)),
+            );
+        }
+        plan.target_path = resolved[name].clone();
+        plan.target_unresolved = false;
+    }
+    Ok(())
+}
+
+/// Runs a submodule's `target_command` in `root` and returns its trimmed
+/// stdout, which must be a single non-empty line.
+fn run_target_command(root: &Path, name: &str, command: &str) -> io::Result<PathBuf> {
+    let output = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(root)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("target_command of submodule '{}' {}", name, reason),
+        )
+    };
+    if !output.status.success() {
+        return Err(invalid(format!("exited with {}", output.status)));
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| invalid("printed a path that is not valid UTF-8".to_string()))?;
+    match stdout.trim() {
+        "" => Err(invalid("printed no path".to_string())),
+        path if path.contains('\n') => Err(invalid(format!(
+            "printed several lines instead of one path: {:?}",
+            path
+        ))),
+        path => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Applies the options that change where plans sync from and to:
+/// `--target-template`, `--resolve-symlinks-in-config` and
+/// `--strip-components`. `target_base` is where default targets live.
+fn apply_path_options(
+    plans: &mut [SyncPlan],
+    options: &SyncOptions,
+    root: &Path,
+    target_base: &Path,
+    stamp: &chrono::DateTime<chrono::Local>,
+) -> io::Result<()> {
+    if let Some(template) = &options.target_template {
+        if plans.len() > 1 && !template.uses("submodule") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--target-template must contain {submodule} when syncing more than one submodule",
+            ));
+        }
+        for plan in plans.iter_mut() {
+            let release = root.join(template.expand(&plan.submodule.name, stamp));
+            plan.target_path = plan.submodule.in_subdir(release);
+        }
+    }
+    if !options.allow_target_outside_parent {
+        check_targets_within_base(plans, target_base)?;
+    }
+    check_compare_dests(plans, options)?;
+    if options.resolve_symlinks_in_config {
+        resolve_source_symlinks(plans, options.verbosity);
+    }
+    if let Some(depth) = options.strip_components {
+        for plan in plans.iter_mut().filter(|p| p.source_path.is_dir()) {
+            let mut roots: Vec<String> = verify::walk_relative(&plan.source_path)?
+                .into_iter()
+                .filter(|(path, is_dir)| *is_dir && path.split('/').count() == depth as usize)
+                .map(|(path, _)| path)
+                .collect();
+            if roots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Submodule '{}' has no directories {} level(s) deep to strip",
+                        plan.submodule.name, depth
+                    ),
+                ));
+            }
+            roots.sort();
+            plan.strip_roots = roots;
+        }
+    }
+    if let Some(predicate) = &options.content_filter {
+        for plan in plans.iter_mut().filter(|p| p.source_path.is_dir()) {
+            let mut matches = Vec::new();
+            for path in selected_files(plan, options)? {
+                if content_matches(predicate, &plan.source_path, &path)? {
+                    matches.push(path);
+                }
+            }
+            plan.content_matches = Some(matches);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `--content-filter` command on `path` in `source` and returns
+/// whether it accepted the file.
+fn content_matches(predicate: &str, source: &Path, path: &str) -> io::Result<bool> {
+    let status = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", predicate))
+        .arg("sh")
+        .arg(path)
+        .current_dir(source)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+/// Escapes rsync's wildcard characters so `path` matches only itself.
+fn literal_pattern(path: &str) -> String {
+    let mut pattern = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// Fails unless every local target lies within `target_base`: the
+/// configured `target_base`, or the monorepo's parent directory when none is
+/// set. Paths are compared after resolving `.` and `..` lexically; symlinks
+/// are not followed.
+fn check_targets_within_base(plans: &[SyncPlan], target_base: &Path) -> io::Result<()> {
+    let base = normalize_lexically(target_base);
+    for plan in plans {
+        if rsync::is_remote(&plan.target_path) || plan.target_unresolved {
+            continue;
+        }
+        let target = normalize_lexically(&plan.target_path);
+        if !target.starts_with(&base) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Target of submodule '{}' resolves to {:?}, outside {:?}; \
+                     pass --allow-target-outside-parent if this is intended",
+                    plan.submodule.name,
+                    display::path(&target),
+                    display::path(&base)
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fails if a `--compare-dest` reference directory of a local target is
+/// missing, since rsync would silently compare against nothing.
+fn check_compare_dests(plans: &[SyncPlan], options: &SyncOptions) -> io::Result<()> {
+    for plan in plans.iter().filter(|p| !rsync::is_remote(&p.target_path)) {
+        for compare_dest in &options.compare_dest {
+            let reference = backup_path(compare_dest, &plan.submodule.name);
+            if !reference.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "--compare-dest directory {:?} for submodule '{}' does not exist",
+                        display::path(&reference),
+                        plan.submodule.name
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves `.` and `..` components of `path` without touching the
+/// filesystem.
+pub fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Replaces each plan's source path with its canonical form, reporting the
+/// paths that went through a symlink when `verbosity` is verbose. Missing
+/// sources are left as-is so the usual "does not exist" handling applies.
+fn resolve_source_symlinks(plans: &mut [SyncPlan], verbosity: Verbosity) {
+    for plan in plans {
+        let Ok(real_path) = fs::canonicalize(&plan.source_path) else {
+            continue;
+        };
+        let lexical = plan.source_path.components().collect::<PathBuf>();
+        if verbosity == Verbosity::Verbose && real_path != lexical {
+            println!(
+                "Resolved source of '{}': {:?} -> {:?}",
+                plan.submodule.name,
+                display::path(&plan.source_path),
+                display::path(&real_path)
+            );
+        }
+        plan.source_path = real_path;
+    }
+}
+
+/// Returns an rsync pattern anchored at `source` that matches `dir`, if `dir`
+/// lies inside `source`. Both paths are canonicalized so `..` components and
+/// symlinks cannot hide the containment.
+fn contained_pattern(source: &Path, dir: &Path) -> Option<String> {
+    let source = fs::canonicalize(source).ok()?;
+    let dir = fs::canonicalize(dir).ok()?;
+    let relative = dir.strip_prefix(&source).ok()?;
+    Some(format!("/{}/", relative.display()))
+}
+
+/// The `--max-depth` rule: excludes every directory `depth + 1` levels
+/// below the source root, and with it everything inside.
+fn max_depth_rule(depth: u32) -> String {
+    format!("--exclude=/{}", "*/".repeat(depth as usize + 1))
+}
+
+/// Builds the rsync command for `plan`, applying the run-wide filters from
+/// `options`. A submodule's `delete` setting can turn `delete` off but never
+/// on, so `--abort-on-delete` keeps its guarantee.
+fn rsync_command(plan: &SyncPlan, options: &SyncOptions, delete: bool) -> RsyncCommand {
+    let delete = delete && plan.submodule.delete.unwrap_or(true);
+    let mut rsync_cmd = RsyncCommand::new(
+        plan.submodule,
+        plan.archive,
+        &plan.source_path,
+        &plan.target_path,
+        delete,
+    );
+    for (key, value) in &options.rsync_env {
+        rsync_cmd.env(key, value);
+    }
+    rsync_cmd.target_trailing_slash(options.target_trailing_slash);
+    if !plan.strip_roots.is_empty() {
+        rsync_cmd.relative_sources(&plan.source_path, &plan.strip_roots);
+    }
+    // rsync stops at the first matching rule, so the depth cap goes before
+    // every include that could let deeper files through.
+    if let Some(depth) = options.max_depth {
+        rsync_cmd.filter(max_depth_rule(depth));
+    }
+    if let Some(rules) = &plan.source_rules {
+        rsync_cmd
+            .source_rules(rules)
+            .filter(format!("--exclude=/{}", config::SOURCE_RULES_FILE));
+    }
+    rsync_cmd.global_rules(plan.global_include, plan.global_exclude);
+    // Run-level rules come before the submodule's, so listing the accepted
+    // files and excluding the rest overrides its patterns.
+    if let Some(matches) = &plan.content_matches {
+        let mut dirs = BTreeSet::new();
+        for path in matches {
+            let mut parent = Path::new(path).parent();
+            while let Some(dir) = parent.filter(|d| !d.as_os_str().is_empty()) {
+                dirs.insert(dir.to_string_lossy().into_owned());
+                parent = dir.parent();
+            }
+        }
+        // Like the submodule's rules, these only pick what is sent once
+        // deletion is scoped; the scope decides what may be deleted.
+        let scoped = delete && !options.delete_scope.is_empty();
+        let rules = dirs
+            .iter()
+            .map(|dir| format!("--include=/{}/", literal_pattern(dir)))
+            .chain(
+                matches
+                    .iter()
+                    .map(|path| format!("--include=/{}", literal_pattern(path))),
+            )
+            .chain(std::iter::once("--exclude=*".to_string()));
+        for rule in rules {
+            rsync_cmd.filter(if scoped {
+                rsync::sender_only(&rule)
+            } else {
+                rule
+            });
+        }
+    }
+    // Protect rules go first so no later include can expose the files to
+    // deletion; the delete scope's rules go last so excludes win over them.
+    if delete {
+        for pattern in &options.preserve_target {
+            rsync_cmd.filter(format!("--filter=P {}", pattern));
+        }
+        rsync_cmd.delete_scope(&options.delete_scope);
+    }
+    if !options.no_config_dir_exclude {
+        if let Some(pattern) = &plan.config_dir_pattern {
+            rsync_cmd.filter(format!("--exclude={}", pattern));
+        }
+    }
+    for ext in &options.exclude_ext {
+        rsync_cmd.filter(format!("--exclude=*.{}", ext));
+    }
+    if options.target_lock {
+        // Excluded files are also protected from --delete.
+        rsync_cmd.filter(format!("--exclude=/{}", TARGET_LOCK_FILE));
+    }
+    if options.exclude_build_artifacts {
+        for pattern in BUILD_ARTIFACTS
+            .iter()
+            .copied()
+            .chain(plan.build_artifacts.iter().map(String::as_str))
+        {
+            rsync_cmd.filter(format!("--exclude={}", pattern));
+        }
+    }
+    for pattern in &options.stdin_excludes {
+        rsync_cmd.filter(format!("--exclude={}", pattern));
+    }
+    if options.safe_links {
+        rsync_cmd.arg("--safe-links");
+    }
+    if options.copy_unsafe_links {
+        rsync_cmd.arg("--copy-unsafe-links");
+    }
+    if options.munge_links {
+        rsync_cmd.arg("--munge-links");
+    }
+    if options.fake_super {
+        rsync_cmd.arg("--fake-super");
+    }
+    if let Some(rate) = options
+        .bwlimit
+        .into_iter()
+        .chain(options.total_bwlimit)
+        .min()
+    {
+        // rsync takes the rate in KiB/s.
+        rsync_cmd.arg(format!("--bwlimit={}", rate.div_ceil(1024)));
+    }
+    if let Some(timeout) = plan.submodule.timeout_secs.or(options.timeout) {
+        rsync_cmd.arg(format!("--timeout={}", timeout));
+    }
+    if options.partial {
+        match &options.partial_dir {
+            Some(dir) => {
+                // rsync refuses --inplace together with --partial-dir.
+                let mut arg = OsString::from("--partial-dir=");
+                arg.push(dir);
+                rsync_cmd.remove_arg("--inplace").arg(arg)
+            }
+            None => rsync_cmd.arg("--partial"),
+        };
+    }
+    if options.no_whole_file {
+        rsync_cmd.arg("--no-whole-file");
+    } else if options.whole_file || !rsync::is_remote(&plan.target_path) {
+        rsync_cmd.arg("--whole-file");
+    }
+    for compare_dest in &options.compare_dest {
+        let mut arg = OsString::from("--compare-dest=");
+        arg.push(backup_path(compare_dest, &plan.submodule.name));
+        rsync_cmd.arg(arg);
+    }
+    if let Some(backup_dir) = &options.backup_dir {
+        let mut arg = OsString::from("--backup-dir=");
+        arg.push(backup_path(backup_dir, &plan.submodule.name));
+        rsync_cmd.arg("--backup").arg(arg);
+    }
+    if delete {
+        if let Some(max_delete) = options.max_delete {
+            rsync_cmd.arg(format!("--max-delete={}", max_delete));
+        }
+        if options.delete_delay {
+            rsync_cmd.arg("--delete-delay");
+        }
+    }
+    for arg in plan.submodule.extra_rsync_args.iter().flatten() {
+        rsync_cmd.arg(arg);
+    }
+    rsync_cmd
+}
+
+/// Builds the command that actually transfers `plan`: the filtered rsync
+/// command plus the flags the sync loop depends on.
+fn transfer_command(plan: &SyncPlan, options: &SyncOptions, delete: bool) -> RsyncCommand {
+    let mut rsync_cmd = rsync_command(plan, options, delete);
+    if options.move_files {
+        rsync_cmd.arg("--remove-source-files");
+    }
+    match &options.out_format {
+        Some(format) => rsync_cmd.arg(format!("--out-format={}", format)),
+        None => rsync_cmd.arg("--itemize-changes"),
+    };
+    // With --out-format every output line counts as a change, so -v's
+    // extra lines would be miscounted.
+    if options.verbosity == Verbosity::Verbose && options.out_format.is_none() {
+        rsync_cmd.arg("-v");
+    }
+    rsync_cmd
+}
+
+/// Runs `plan` as up to `workers` concurrent rsync processes, each scoped to
+/// a share of the source's top-level directories and never deleting,
+/// followed by the full transfer command, which alone handles deletions.
+/// Returns the first failing status, or the final pass's status and the
+/// output of all processes. Progress messages are written to `out`.
+fn run_split(
+    plan: &SyncPlan,
+    options: &SyncOptions,
+    delete: bool,
+    workers: usize,
+    out: &mut impl Write,
+) -> io::Result<(ExitStatus, Vec<String>)> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(&plan.source_path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    dirs.sort();
+
+    let mut groups = vec![Vec::new(); workers.min(dirs.len())];
+    let group_count = groups.len();
+    for (index, dir) in dirs.into_iter().enumerate() {
+        groups[index % group_count].push(dir);
+    }
+    let commands: Vec<RsyncCommand> = groups
+        .iter()
+        .map(|group| {
+            let mut rsync_cmd = transfer_command(plan, options, false);
+            for dir in group {
+                rsync_cmd.filter(format!("--include=/{}/", dir));
+            }
+            rsync_cmd.filter("--exclude=/*");
+            rsync_cmd
+        })
+        .collect();
+    if options.verbosity == Verbosity::Verbose {
+        writeln!(
+            out,
+            "Running {} rsync workers for {}",
+            commands.len(),
+            plan.submodule.name
+        )?;
+    }
+    let results: Vec<io::Result<(ExitStatus, Vec<String>)>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = commands
+            .iter()
+            .map(|rsync_cmd| scope.spawn(|| rsync::run_captured(rsync_cmd)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("rsync worker panicked"))
+            .collect()
+    });
+
+    let mut lines = Vec::new();
+    for result in results {
+        let (status, worker_lines) = result?;
+        if !status.success() {
+            return Ok((status, worker_lines));
+        }
+        lines.extend(worker_lines);
+    }
+
+    let rsync_cmd = transfer_command(plan, options, delete);
+    if options.verbosity == Verbosity::Verbose {
+        writeln!(out, "Running: {}", rsync_cmd.shell_line())?;
+    }
+    let (status, final_lines) = rsync::run_captured(&rsync_cmd)?;
+    lines.extend(final_lines);
+    Ok((status, lines))
+}
+
+/// The lines of a transfer command's output that report a change: itemized
+/// lines by default, every non-empty line with `--out-format`.
+fn change_lines<'l>(lines: &'l [String], options: &SyncOptions) -> Vec<&'l String> {
+    lines
+        .iter()
+        .filter(|line| match options.out_format {
+            Some(_) => !line.trim().is_empty(),
+            None => rsync::parse_itemized(line).is_some(),
+        })
+        .collect()
+}
+
+/// Dry-runs every configured submodule the way `sync` would and prints how
+/// many files each would create, update and delete in its target.
+pub fn status() -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+    if app_config.submodules.is_empty() {
+        println!("No submodules configured.");
+        return Ok(());
+    }
+    rsync::check_available()?;
+
+    let options = SyncOptions::default();
+    let mut plans = plan_submodules(&root, &app_config, None)?;
+    let target_base = app_config.target_base(&root)?;
+    apply_path_options(
+        &mut plans,
+        &options,
+        &root,
+        &target_base,
+        &chrono::Local::now(),
+    )?;
+    for plan in &plans {
+        let name = &plan.submodule.name;
+        if !plan.source_path.is_dir() {
+            println!(
+                "{}: source {:?} is missing",
+                name,
+                display::path(&plan.source_path)
+            );
+            continue;
+        }
+        if plan.target_unresolved {
+            println!("{}: target comes from target_command, not checked", name);
+            continue;
+        }
+        let mut rsync_cmd = transfer_command(plan, &options, true);
+        rsync_cmd.arg("--dry-run");
+        let (status, lines) = rsync::run_captured(&rsync_cmd)?;
+        if !status.success() {
+            println!("{}: rsync exited with {}", name, status);
+            continue;
+        }
+
+        let (mut created, mut updated, mut deleted) = (0, 0, 0);
+        for line in &lines {
+            match rsync::parse_itemized(line) {
+                Some((ItemChange::Created, _)) => created += 1,
+                Some((ItemChange::Updated, _)) => updated += 1,
+                Some((ItemChange::Deleted, _)) => deleted += 1,
+                None => {}
+            }
+        }
+        let counts: Vec<String> = [
+            (created, "to create"),
+            (updated, "to update"),
+            (deleted, "to delete"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, label)| format!("{} {}", count, label))
+        .collect();
+        if counts.is_empty() {
+            println!("{}: up to date", name);
+        } else {
+            println!("{}: {}", name, counts.join(", "));
+        }
+        if !plan.submodule.meta.is_null() {
+            println!("  meta: {}", plan.submodule.meta);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the rsync command `sync` would run for one submodule as a shell
+/// command line, without running it.
+pub fn show_command(name: &str, options: &SyncOptions) -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let mut app_config = config::load_or_create_config(&config_dir)?;
+    override_patterns(&mut app_config, options);
+    if app_config.find(name).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Submodule '{}' not found in config", name),
+        ));
+    }
+
+    let selected = [name.to_string()];
+    let mut plans = plan_submodules(&root, &app_config, Some(&selected))?;
+    let target_base = app_config.target_base(&root)?;
+    apply_path_options(
+        &mut plans,
+        options,
+        &root,
+        &target_base,
+        &chrono::Local::now(),
+    )?;
+    let delete = !options.abort_on_delete;
+    for plan in &plans {
+        let mut rsync_cmd = transfer_command(plan, options, delete);
+        if options.dry_run || options.quiet_dry_run {
+            rsync_cmd.arg("--dry-run");
+        }
+        println!("{}", rsync_cmd.shell_line());
+    }
+    Ok(())
+}
+
+/// Applies the `--include` and `--exclude` overrides to every submodule of the
+/// loaded `app_config`, replacing or (with `--append-patterns`) extending its
+/// patterns. Only the in-memory copy changes.
+fn override_patterns(app_config: &mut AppConfig, options: &SyncOptions) {
+    for submodule in &mut app_config.submodules {
+        for (patterns, overrides) in [
+            (&mut submodule.include, &options.include),
+            (&mut submodule.exclude, &options.exclude),
+        ] {
+            if overrides.is_empty() {
+                continue;
+            }
+            let patterns = patterns.get_or_insert_with(Vec::new);
+            if !options.append_patterns {
+                patterns.clear();
+            }
+            patterns.extend(overrides.iter().cloned());
+        }
+    }
+}
+
+/// A sync run that stopped on an error, with the results it had recorded
+/// by then.
+#[derive(Debug)]
+pub struct SyncAborted {
+    pub error: io::Error,
+    pub summary: SyncSummary,
+}
+
+/// Syncs the configured submodules of the monorepo containing the current
+/// directory to their targets. A run that stops early still returns the
+/// results recorded until then.
+pub fn sync_submodules(
+    selected: Option<&[String]>,
+    options: &SyncOptions,
+) -> Result<SyncSummary, SyncAborted> {
+    let mut summary = SyncSummary {
+        json_lines: options.json_lines,
+        ..SyncSummary::default()
+    };
+    match sync_into(selected, options, &mut summary) {
+        Ok(()) => Ok(summary),
+        Err(error) => Err(SyncAborted { error, summary }),
+    }
+}
+
+/// Runs the sync for `sync_submodules`, recording results into `summary`.
+fn sync_into(
+    selected: Option<&[String]>,
+    options: &SyncOptions,
+    summary: &mut SyncSummary,
+) -> io::Result<()> {
+    let run_started = Instant::now();
+    let mut profile = Profile::default();
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let mut app_config = config::load_or_create_config(&config_dir)?;
+    override_patterns(&mut app_config, options);
+    profile.config_load = run_started.elapsed();
+
+    if options.show_preset {
+        println!("--exclude-build-artifacts excludes:");
+        for pattern in BUILD_ARTIFACTS {
+            println!("  {}", pattern);
+        }
+        for pattern in &app_config.build_artifacts {
+            println!("  {}  (build_artifacts in config)", pattern);
+        }
+        return Ok(());
+    }
+
+    if app_config.submodules.is_empty() {
+        if options.verbosity > Verbosity::Quiet {
+            println!("No submodules configured.");
+        }
+        return Ok(());
+    }
+
+    let tagged = |submodule: &SubmoduleConfig| options.tag.iter().any(|t| submodule.has_tag(t));
+    let union = selected.is_some() && options.tag_combine == TagCombine::Union;
+    let mut plans = match selected {
+        Some(names) if union => {
+            let mut names = names.to_vec();
+            for submodule in app_config.submodules.iter().filter(|s| tagged(s)) {
+                if !names.contains(&submodule.name) {
+                    names.push(submodule.name.clone());
+                }
+            }
+            plan_submodules(&root, &app_config, Some(&names))?
+        }
+        _ => plan_submodules(&root, &app_config, selected)?,
+    };
+    if !options.tag.is_empty() && !union {
+        plans.retain(|plan| tagged(plan.submodule));
+    }
+    summary.selected = plans.len();
+    let run_stamp = chrono::Local::now();
+    let target_base = app_config.target_base(&root)?;
+    let mut resolved_targets = HashMap::new();
+    resolve_target_commands(&mut plans, &root, &target_base, &mut resolved_targets)?;
+    apply_path_options(&mut plans, options, &root, &target_base, &run_stamp)?;
+    if options.preflight {
+        preflight(&plans, options)?;
+    }
+    plans.retain(|plan| {
+        let exists = plan.source_path.is_dir();
+        if !exists {
+            eprintln!(
+                "Source path {:?} for submodule '{}' does not exist, skipping.",
+                display::path(&plan.source_path),
+                plan.submodule.name
+            );
+            summary.failed += 1;
+            summary.record(plan, SyncOutcome::SourceMissing, None, 0, Duration::ZERO);
+        }
+        exists
+    });
+
+    if options.no_config_dir_exclude {
+        for plan in plans.iter().filter(|p| p.config_dir_pattern.is_some()) {
+            eprintln!(
+                "Warning: source of '{}' contains {}, which will be synced to {:?}.",
+                plan.submodule.name,
+                config::CONFIG_DIR,
+                display::path(&plan.target_path)
+            );
+        }
+    }
+
+    if options.local_only {
+        filter_not_local(&mut plans, options.verbosity, summary)?;
+    }
+    let missing =
+        |plan: &SyncPlan| !rsync::is_remote(&plan.target_path) && !plan.target_path.is_dir();
+    if options.on_missing_target == OnMissingTarget::Error {
+        if let Some(plan) = plans.iter().find(|plan| missing(plan)) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Target {:?} of submodule '{}' does not exist (--on-missing-target error)",
+                    display::path(&plan.target_path),
+                    plan.submodule.name
+                ),
+            ));
+        }
+    }
+    if options.on_missing_target == OnMissingTarget::Skip
+        || (options.target_existing_required && selected.is_none())
+    {
+        plans.retain(|plan| {
+            let exists = !missing(plan);
+            if !exists {
+                if options.verbosity > Verbosity::Quiet {
+                    println!(
+                        "{}: target {:?} does not exist, skipping.",
+                        plan.submodule.name,
+                        display::path(&plan.target_path)
+                    );
+                }
+                summary.skipped += 1;
+                summary.record(plan, SyncOutcome::TargetMissing, None, 0, Duration::ZERO);
+            }
+            exists
+        });
+    }
+
+    let mut sync_state = state::load_state(&config_dir)?;
+    if options.since_last_sync {
+        filter_unchanged(&mut plans, &sync_state, options.verbosity, summary)?;
+    }
+
+    // --explain only describes the commands, so it works without rsync.
+    if !options.explain {
+        rsync::check_available()?;
+    }
+    if options.list_files {
+        list_files(&plans, options, summary)?;
+        return Ok(());
+    }
+
+    let delete = !options.abort_on_delete;
+    if options.explain {
+        explain(&plans, options, delete);
+        return Ok(());
+    }
+    if options.debug_filters {
+        debug_filters(&plans, options, delete)?;
+        return Ok(());
+    }
+    if options.dry_run || options.quiet_dry_run {
+        dry_run(&plans, options, delete, summary)?;
+        return Ok(());
+    }
+
+    if options.confirm_target_creation_per_submodule && !options.yes {
+        confirm_target_creation(&mut plans, options.verbosity, summary)?;
+    }
+
+    let dry_runs_started = Instant::now();
+    if options.estimate {
+        confirm_estimate(&plans, options, delete)?;
+    }
+    if options.abort_on_delete {
+        check_no_deletions(&plans, options)?;
+    } else if let Some(threshold) = options.confirm_delete_threshold {
+        confirm_delete_threshold(&plans, options, threshold)?;
+    } else if !options.yes {
+        confirm_deletions(&plans, options)?;
+    }
+
+    let audit_dir = match (&options.audit_dir, &options.output_dir) {
+        (Some(audit_dir), _) => Some(audit_dir.clone()),
+        (None, Some(output_dir)) => Some(output_dir.join("audit")),
+        (None, None) => None,
+    };
+    let mut audit_record = match &audit_dir {
+        Some(_) => Some(start_audit(&plans, options, delete, &config_dir)?),
+        None => None,
+    };
+    profile.dry_runs = dry_runs_started.elapsed();
+
+    if options.move_files {
+        confirm_move(&plans, options.yes)?;
+    }
+    if options.pre_clean_target {
+        confirm_pre_clean(&plans, options.yes)?;
+    }
+    let chown_spec = ownership_spec(options);
+
+    let mut parallel = match options.jobs {
+        Some(jobs) if jobs > 1 => {
+            Some(transfer_parallel(&plans, options, delete, jobs).into_iter())
+        }
+        _ => None,
+    };
+    for (index, plan) in plans.iter().enumerate() {
+        let name = &plan.submodule.name;
+        let transfer = match &mut parallel {
+            Some(results) => results.next().expect("one transfer per plan"),
+            None => transfer_submodule(plan, options, delete, &mut io::stdout()),
+        }?;
+        let (status, lines, vanished, command_line, started, elapsed) = match transfer {
+            Transfer::LowSpace { elapsed } | Transfer::PreSyncFailed { elapsed } => {
+                let outcome = match transfer {
+                    Transfer::LowSpace { .. } => SyncOutcome::InsufficientSpace,
+                    _ => SyncOutcome::HookFailed,
+                };
+                summary.failed += 1;
+                summary.record(plan, outcome, None, 0, elapsed);
+                if let Some(record) = &mut audit_record {
+                    record.submodules[index].outcome = outcome.as_str().to_string();
+                }
+                continue;
+            }
+            Transfer::Ran {
+                status,
+                lines,
+                vanished,
+                created_target,
+                command_line,
+                started,
+                rsync_elapsed,
+                elapsed,
+            } => {
+                // Templated releases are kept on purpose; recording them would
+                // make every older release an orphan.
+                if created_target && options.target_template.is_none() {
+                    sync_state.created_targets.insert(plan.target_path.clone());
+                }
+                profile.submodules.push((name.clone(), rsync_elapsed));
+                (status, lines, vanished, command_line, started, elapsed)
+            }
+        };
+        if let Some(output_dir) = &options.output_dir {
+            write_trace(output_dir, name, &command_line, &lines)?;
+        }
+
+        let outcome;
+        let mut changes = 0;
+        if status.success() || vanished {
+            let changed = change_lines(&lines, options);
+            if options.out_format.is_some() {
+                for line in &changed {
+                    println!("{}", line);
+                }
+            } else if options.verbosity == Verbosity::Verbose {
+                for line in &lines {
+                    println!("  {}", line);
+                }
+            }
+            changes = changed.len();
+            if options.verbosity > Verbosity::Quiet {
+                if options.report_unchanged && changes == 0 {
+                    println!(
+                        "{} is already up to date in {:?}",
+                        name,
+                        display::path(&plan.target_path)
+                    );
+                } else {
+                    println!(
+                        "Successfully synced {} to {:?} ({} change(s))",
+                        name,
+                        display::path(&plan.target_path),
+                        changes
+                    );
+                }
+            }
+            if changes > 0 {
+                summary.changed += 1;
+            }
+            let post_sync_ok = match &plan.submodule.post_sync {
+                Some(_) if rsync::is_remote(&plan.target_path) => {
+                    eprintln!(
+                        "Warning: cannot run the post_sync hook of '{}' in a remote target.",
+                        name
+                    );
+                    true
+                }
+                Some(hook) => run_hook(
+                    name,
+                    "post_sync",
+                    hook,
+                    &plan.target_path,
+                    options.verbosity,
+                    &mut io::stdout(),
+                )?,
+                None => true,
+            };
+            let validated = match &plan.submodule.validate_command {
+                _ if !post_sync_ok => true,
+                Some(_) if rsync::is_remote(&plan.target_path) => {
+                    eprintln!(
+                        "Warning: cannot run the validate_command of '{}' in a remote target.",
+                        name
+                    );
+                    true
+                }
+                Some(command) => run_hook(
+                    name,
+                    "validate_command",
+                    command,
+                    &plan.target_path,
+                    options.verbosity,
+                    &mut io::stdout(),
+                )?,
+                None => true,
+            };
+            // Runs whatever the hooks did, so the cleanup is never skipped.
+            let cleaned = match &plan.submodule.cleanup_command {
+                Some(_) if rsync::is_remote(&plan.target_path) => {
+                    eprintln!(
+                        "Warning: cannot run the cleanup_command of '{}' in a remote target.",
+                        name
+                    );
+                    true
+                }
+                Some(command) => run_hook(
+                    name,
+                    "cleanup_command",
+                    command,
+                    &plan.target_path,
+                    options.verbosity,
+                    &mut io::stdout(),
+                )?,
+                None => true,
+            };
+            match &chown_spec {
+                _ if !post_sync_ok || !cleaned => {
+                    summary.failed += 1;
+                    outcome = SyncOutcome::HookFailed;
+                }
+                _ if !validated => {
+                    summary.failed += 1;
+                    outcome = SyncOutcome::ValidationFailed;
+                    if let (true, Some(backup_dir)) =
+                        (options.rollback_on_validation_failure, &options.backup_dir)
+                    {
+                        roll_back(plan, backup_dir)?;
+                    }
+                }
+                Some(spec) if !change_owner(spec, &plan.target_path)? => {
+                    summary.failed += 1;
+                    outcome = SyncOutcome::ChownFailed;
+                }
+                _ => {
+                    summary.synced += 1;
+                    sync_state.last_sync.insert(name.clone(), started);
+                    outcome = SyncOutcome::Synced;
+                    if options.current_link {
+                        update_current_link(&root, options, name, &run_stamp)?;
+                    }
+                }
+            }
+        } else {
+            outcome = failure_outcome(status.code());
+            match outcome {
+                SyncOutcome::TimedOut => eprintln!(
+                    "Failed to sync {}: no data moved for {} seconds, timed out",
+                    name,
+                    plan.submodule
+                        .timeout_secs
+                        .or(options.timeout)
+                        .unwrap_or_default()
+                ),
+                SyncOutcome::DeleteLimitExceeded => eprintln!(
+                    "{}: deletion limit exceeded; {}+ files would be deleted, aborting this submodule",
+                    name,
+                    options.max_delete.unwrap_or_default()
+                ),
+                _ => eprintln!("Failed to sync {}: rsync exited with {}", name, status),
+            }
+            summary.count_failure(outcome);
+        }
+
+        summary.record(plan, outcome, status.code(), changes, elapsed);
+        if let Some(record) = &mut audit_record {
+            let entry = &mut record.submodules[index];
+            entry.outcome = outcome.as_str().to_string();
+            entry.exit_code = status.code();
+        }
+    }
+
+    if options.report_unchanged && options.verbosity > Verbosity::Quiet {
+        report_unchanged(summary);
+    }
+
+    if let (Some(audit_dir), Some(record)) = (&audit_dir, &audit_record) {
+        let path = audit::write_record(audit_dir, record)?;
+        if options.verbosity > Verbosity::Quiet {
+            println!("Audit record written to {:?}", display::path(&path));
+        }
+    }
+
+    if options.delete_orphan_targets {
+        let mut all_plans = plan_submodules(&root, &app_config, None)?;
+        resolve_target_commands(&mut all_plans, &root, &target_base, &mut resolved_targets)?;
+        let configured: Vec<&Path> = all_plans.iter().map(|p| p.target_path.as_path()).collect();
+        delete_orphan_targets(&configured, &mut sync_state, options)?;
+    }
+
+    state::save_state(&config_dir, &sync_state)?;
+    if !summary.results.is_empty() {
+        history::append(&config_dir, &run_stamp.to_rfc3339(), summary)?;
+    }
+    if options.profile {
+        profile.print(run_started.elapsed());
+    }
+    Ok(())
+}
+
+/// Lists which synced submodules changed their targets and which were
+/// already up to date.
+fn report_unchanged(summary: &SyncSummary) {
+    let (unchanged, changed): (Vec<&SyncResult>, Vec<&SyncResult>) = summary
+        .results
+        .iter()
+        .filter(|r| r.outcome == SyncOutcome::Synced)
+        .partition(|r| r.changes == 0);
+    let names = |results: &[&SyncResult]| match results {
+        [] => "(none)".to_string(),
+        results => results
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    println!("Changed: {}", names(&changed));
+    println!("Already up to date: {}", names(&unchanged));
+}
+
+/// The result of a submodule's transfer phase, before it is recorded.
+enum Transfer {
+    /// The target lacks the `--min-free-space`; nothing was run.
+    LowSpace { elapsed: Duration },
+    /// The `pre_sync` hook failed; rsync was not run.
+    PreSyncFailed { elapsed: Duration },
+    Ran {
+        status: ExitStatus,
+        lines: Vec<String>,
+        /// Files vanished again during the `--retry-on-vanished` retry,
+        /// which counts as synced.
+        vanished: bool,
+        created_target: bool,
+        /// The rsync command as run, with this transfer's share of
+        /// `--total-bwlimit`.
+        command_line: String,
+        /// When the transfer started, in seconds since the Unix epoch.
+        started: u64,
+        rsync_elapsed: Duration,
+        elapsed: Duration,
+    },
+}
+
+/// Checks free space, creates the target and runs rsync for one submodule,
+/// retrying once if `--retry-on-vanished` applies. Progress messages are
+/// written to `out`.
+fn transfer_submodule(
+    plan: &SyncPlan,
+    options: &SyncOptions,
+    delete: bool,
+    out: &mut impl Write,
+) -> io::Result<Transfer> {
+    let name = &plan.submodule.name;
+    let submodule_started = Instant::now();
+    if options.json_lines {
+        emit_event(&Event::Start {
+            name,
+            source: &plan.source_path,
+            target: &plan.target_path,
+        });
+    }
+    if let Some(hook) = &plan.submodule.pre_sync {
+        if !run_hook(
+            name,
+            "pre_sync",
+            hook,
+            &plan.source_path,
+            options.verbosity,
+            out,
+        )? {
+            return Ok(Transfer::PreSyncFailed {
+                elapsed: submodule_started.elapsed(),
+            });
+        }
+    }
+    if let Some(min_free) = options.min_free_space {
+        if !has_free_space(plan, options, delete, min_free)? {
+            if options.abort_on_low_space {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!("Not enough free space to sync '{}'", name),
+                ));
+            }
+            return Ok(Transfer::LowSpace {
+                elapsed: submodule_started.elapsed(),
+            });
+        }
+    }
+    if options.pre_clean_target && pre_cleans(plan) && plan.target_path.is_dir() {
+        clean_dir(&plan.target_path)?;
+        if options.verbosity > Verbosity::Quiet {
+            writeln!(
+                out,
+                "Emptied target directory {:?}",
+                display::path(&plan.target_path)
+            )?;
+        }
+    }
+    // rsync creates a missing remote target itself, but not its parents.
+    let created_target = !options.assume_target_clean
+        && !rsync::is_remote(&plan.target_path)
+        && !plan.target_path.exists();
+    if created_target {
+        fs::create_dir_all(&plan.target_path)?;
+        if let Some(mode) = options.target_dir_mode {
+            fs::set_permissions(&plan.target_path, fs::Permissions::from_mode(mode))?;
+        }
+        if options.verbosity > Verbosity::Quiet {
+            writeln!(
+                out,
+                "Created target directory {:?}",
+                display::path(&plan.target_path)
+            )?;
+        }
+    }
+    let _lock = match options.target_lock {
+        true if rsync::is_remote(&plan.target_path) => {
+            eprintln!(
+                "Warning: --target-lock cannot mark the remote target of '{}'.",
+                name
+            );
+            None
+        }
+        true => Some(TargetLock::acquire(&plan.target_path)?),
+        false => None,
+    };
+
+    let started = state::now_secs();
+    let rsync_started = Instant::now();
+    let mut run = || match options.parallel_within_submodule {
+        Some(workers) => run_split(plan, options, delete, workers as usize, out),
+        None => {
+            let rsync_cmd = transfer_command(plan, options, delete);
+            if options.verbosity == Verbosity::Verbose {
+                writeln!(out, "Running: {}", rsync_cmd.shell_line())?;
+            }
+            rsync::run_captured(&rsync_cmd)
+        }
+    };
+    let (mut status, mut lines) = run()?;
+    let mut vanished = false;
+    if options.retry_on_vanished && status.code() == Some(rsync::EXIT_VANISHED) {
+        eprintln!(
+            "{}: files vanished during the transfer, retrying once",
+            name
+        );
+        (status, lines) = run()?;
+        if status.code() == Some(rsync::EXIT_VANISHED) {
+            eprintln!(
+                "Warning: {}: files vanished again during the retry; treating as synced",
+                name
+            );
+            vanished = true;
+        }
+    }
+    Ok(Transfer::Ran {
+        status,
+        lines,
+        vanished,
+        created_target,
+        command_line: transfer_command(plan, options, delete).shell_line(),
+        started,
+        rsync_elapsed: rsync_started.elapsed(),
+        elapsed: submodule_started.elapsed(),
+    })
+}
+
+/// Copies the files the sync moved into `backup_dir` back over `plan`'s
+/// target after its validation failed.
+fn roll_back(plan: &SyncPlan, backup_dir: &Path) -> io::Result<()> {
+    let name = &plan.submodule.name;
+    let backup = backup_path(backup_dir, name);
+    if !backup.is_dir() {
+        eprintln!(
+            "{}: nothing to roll back; the sync did not back up any files.",
+            name
+        );
+        return Ok(());
+    }
+    let rsync_cmd = RsyncCommand::copy(plan.archive, &backup, &plan.target_path, false);
+    let (status, _) = rsync::run_captured(&rsync_cmd)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Failed to roll back {}: rsync exited with {}",
+            name, status
+        )));
+    }
+    eprintln!("{}: rolled back from {:?}", name, display::path(&backup));
+    Ok(())
+}
+
+/// Runs one of a submodule's hook commands with `sh -c` in `dir`,
+/// writing its output to `out` unless `verbosity` is quiet. Returns whether
+/// it succeeded.
+fn run_hook(
+    name: &str,
+    kind: &str,
+    hook: &str,
+    dir: &Path,
+    verbosity: Verbosity,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    if verbosity == Verbosity::Verbose {
+        writeln!(out, "Running {} hook for {}: {}", kind, name, hook)?;
+    }
+    let output = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .current_dir(dir)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if verbosity > Verbosity::Quiet {
+        out.write_all(&output.stdout)?;
+    }
+    if !output.status.success() {
+        eprintln!("{}: {} hook exited with {}", name, kind, output.status);
+    }
+    Ok(output.status.success())
+}
+
+/// The `--total-bwlimit` budget shared by the parallel transfers. A share
+/// is fixed when its transfer starts: running transfers are not rebalanced
+/// as others start or finish.
+struct BandwidthBudget {
+    total: u64,
+    workers: usize,
+    /// Rates held by running transfers and how many are running.
+    held: Mutex<(u64, usize)>,
+    /// Signalled when a share is returned.
+    released: Condvar,
+}
+
+impl BandwidthBudget {
+    fn new(total: u64, workers: usize) -> Self {
+        BandwidthBudget {
+            total,
+            workers,
+            held: Mutex::new((0, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes an equal share of the unheld budget for each idle worker, so
+    /// the running transfers never exceed `total` together. rsync reads a
+    /// limit of 0 as unlimited, so with nothing left this waits for a
+    /// running transfer to return its share.
+    fn acquire(&self) -> u64 {
+        let mut held = self.held.lock().expect("sync worker panicked");
+        while self.total.saturating_sub(held.0) == 0 {
+            held = self.released.wait(held).expect("sync worker panicked");
+        }
+        let unheld = self.total.saturating_sub(held.0);
+        let idle = self.workers.saturating_sub(held.1).max(1) as u64;
+        let share = (unheld / idle).clamp(1, unheld);
+        held.0 += share;
+        held.1 += 1;
+        share
+    }
+
+    fn release(&self, share: u64) {
+        let mut held = self.held.lock().expect("sync worker panicked");
+        held.0 = held.0.saturating_sub(share);
+        held.1 = held.1.saturating_sub(1);
+        self.released.notify_all();
+    }
+}
+
+/// Runs the transfer phase of every plan on up to `jobs` threads and returns
+/// the results in plan order. With `--group-by-target-fs` the plans whose
+/// targets share a filesystem form one group, which a single thread works
+/// through in order; otherwise every plan is a group of its own. Each
+/// submodule's progress messages are buffered and printed as one block when
+/// its transfer completes, so concurrent submodules do not interleave.
+fn transfer_parallel(
+    plans: &[SyncPlan],
+    options: &SyncOptions,
+    delete: bool,
+    jobs: usize,
+) -> Vec<io::Result<Transfer>> {
+    let groups = if options.group_by_target_fs {
+        group_by_target_fs(plans)
+    } else {
+        (0..plans.len()).map(|index| vec![index]).collect()
+    };
+    let workers = jobs.min(groups.len());
+    let budget = options
+        .total_bwlimit
+        .map(|total| BandwidthBudget::new(total, workers));
+    let queue = Mutex::new(groups.into_iter());
+    let results: Mutex<BTreeMap<usize, io::Result<Transfer>>> = Mutex::new(BTreeMap::new());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let Some(group) = queue.lock().expect("sync worker panicked").next() else {
+                    break;
+                };
+                for index in group {
+                    let mut out = Vec::new();
+                    let result = match &budget {
+                        Some(budget) => {
+                            let share = budget.acquire();
+                            let options = SyncOptions {
+                                total_bwlimit: Some(share),
+                                ..options.clone()
+                            };
+                            let result =
+                                transfer_submodule(&plans[index], &options, delete, &mut out);
+                            budget.release(share);
+                            result
+                        }
+                        None => transfer_submodule(&plans[index], options, delete, &mut out),
+                    };
+                    print!("{}", String::from_utf8_lossy(&out));
+                    results
+                        .lock()
+                        .expect("sync worker panicked")
+                        .insert(index, result);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .expect("sync worker panicked")
+        .into_values()
+        .collect()
+}
+
+/// Groups plan indices by the device id (`st_dev`) of the filesystem holding
+/// each target. A target that does not exist yet is measured by its nearest
+/// existing ancestor, the filesystem it will be created on. When the device
+/// cannot be determined, for a remote target or when no ancestor can be
+/// read, the target is placed in a group of its own.
+fn group_by_target_fs(plans: &[SyncPlan]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut by_device: BTreeMap<u64, usize> = BTreeMap::new();
+    for (index, plan) in plans.iter().enumerate() {
+        match target_device(&plan.target_path) {
+            Some(device) => match by_device.get(&device) {
+                Some(&group) => groups[group].push(index),
+                None => {
+                    by_device.insert(device, groups.len());
+                    groups.push(vec![index]);
+                }
+            },
+            None => groups.push(vec![index]),
+        }
+    }
+    groups
+}
+
+/// The device id of the filesystem `target` is (or will be created) on.
+fn target_device(target: &Path) -> Option<u64> {
+    if rsync::is_remote(target) {
+        return None;
+    }
+    target
+        .ancestors()
+        .find_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.dev())
+}
+
+/// Writes the rsync command run for a submodule and its output to
+/// `rsync/<submodule>.log` in the `--output-dir`.
+fn write_trace(
+    output_dir: &Path,
+    name: &str,
+    command_line: &str,
+    lines: &[String],
+) -> io::Result<()> {
+    let trace_dir = output_dir.join("rsync");
+    fs::create_dir_all(&trace_dir)?;
+    let mut file = io::BufWriter::new(fs::File::create(trace_dir.join(format!("{}.log", name)))?);
+    writeln!(file, "$ {}", command_line)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.flush()
+}
+
+/// Writes `summary` as `summary.json` to the `--output-dir`, creating it if
+/// needed.
+pub fn write_summary(output_dir: &Path, summary: &SyncSummary) -> io::Result<()> {
+    fs::create_dir_all(output_dir)?;
+    write_summary_file(&output_dir.join("summary.json"), summary)
+}
+
+/// Writes `summary` as pretty-printed JSON to `path`.
+pub fn write_summary_file(path: &Path, summary: &SyncSummary) -> io::Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer_pretty(&mut file, summary)?;
+    file.write_all(b"\n")?;
+    file.flush()
+}
+
+/// Where the time of a `sync --profile` run went.
+#[derive(Default)]
+struct Profile {
+    config_load: Duration,
+    dry_runs: Duration,
+    submodules: Vec<(String, Duration)>,
+}
+
+impl Profile {
+    fn print(&mut self, total: Duration) {
+        self.submodules
+            .sort_by_key(|(_, elapsed)| Reverse(*elapsed));
+        let rsync_total: Duration = self.submodules.iter().map(|(_, d)| *d).sum();
+
+        println!("Profile:");
+        println!(
+            "  {:<24} {:>10.3}s",
+            "config load",
+            self.config_load.as_secs_f64()
+        );
+        println!(
+            "  {:<24} {:>10.3}s",
+            "dry-runs",
+            self.dry_runs.as_secs_f64()
+        );
+        println!(
+            "  {:<24} {:>10.3}s",
+            "rsync (all submodules)",
+            rsync_total.as_secs_f64()
+        );
+        for (name, elapsed) in &self.submodules {
+            println!("    {:<22} {:>10.3}s", name, elapsed.as_secs_f64());
+        }
+        let overhead = total.saturating_sub(self.config_load + self.dry_runs + rsync_total);
+        println!("  {:<24} {:>10.3}s", "other", overhead.as_secs_f64());
+        println!("  {:<24} {:>10.3}s", "total", total.as_secs_f64());
+    }
+}
+
+/// Removes directories the tool created earlier that no configured submodule
+/// targets anymore. Directories it did not create are never considered. The
+/// list before the confirmation goes to stderr; with `--json-lines` each
+/// removal is reported as an event instead of a message.
+fn delete_orphan_targets(
+    configured: &[&Path],
+    sync_state: &mut SyncState,
+    options: &SyncOptions,
+) -> io::Result<()> {
+    sync_state.created_targets.retain(|target| target.is_dir());
+    let orphans: Vec<PathBuf> = sync_state
+        .created_targets
+        .iter()
+        .filter(|target| !configured.contains(&target.as_path()))
+        .cloned()
+        .collect();
+    if orphans.is_empty() {
+        if options.verbosity > Verbosity::Quiet {
+            println!("No orphaned target directories.");
+        }
+        return Ok(());
+    }
+
+    eprintln!("Orphaned target directories (no longer configured):");
+    for orphan in &orphans {
+        eprintln!("  {:?}", display::path(orphan));
+    }
+    let question = format!("Delete {} orphaned target directories?", orphans.len());
+    if !prompt::confirm(&question, options.yes)? {
+        eprintln!("Keeping orphaned target directories.");
+        return Ok(());
+    }
+
+    for orphan in orphans {
+        fs::remove_dir_all(&orphan)?;
+        if options.json_lines {
+            emit_event(&Event::OrphanRemoved { target: &orphan });
+        } else if options.verbosity > Verbosity::Quiet {
+            println!("Removed {:?}", display::path(&orphan));
+        }
+        sync_state.created_targets.remove(&orphan);
+    }
+    Ok(())
+}
+
+/// Drops plans whose source has no files modified since the submodule's last
+/// recorded sync. Timestamps are compared with a safety margin, and a
+/// recorded time in the future (clock moved backwards) always syncs, so clock
+/// skew errs towards syncing rather than skipping.
+fn filter_unchanged(
+    plans: &mut Vec<SyncPlan>,
+    sync_state: &SyncState,
+    verbosity: Verbosity,
+    summary: &mut SyncSummary,
+) -> io::Result<()> {
+    let now = state::now_secs();
+    let mut unchanged = Vec::new();
+    for (index, plan) in plans.iter().enumerate() {
+        let Some(&last_sync) = sync_state.last_sync.get(&plan.submodule.name) else {
+            continue;
+        };
+        if last_sync > now {
+            continue;
+        }
+        let since = last_sync.saturating_sub(CLOCK_SKEW_MARGIN_SECS);
+        if !state::modified_since(&plan.source_path, since)? {
+            unchanged.push(index);
+        }
+    }
+
+    for index in unchanged.into_iter().rev() {
+        let plan = plans.remove(index);
+        if verbosity > Verbosity::Quiet {
+            println!(
+                "{}: no changes since last sync, skipping.",
+                plan.submodule.name
+            );
+        }
+        summary.skipped += 1;
+        summary.record(&plan, SyncOutcome::Unchanged, None, 0, Duration::ZERO);
+    }
+    Ok(())
+}
+
+/// Drops plans whose target is on a remote host or a network filesystem,
+/// reporting each one.
+fn filter_not_local(
+    plans: &mut Vec<SyncPlan>,
+    verbosity: Verbosity,
+    summary: &mut SyncSummary,
+) -> io::Result<()> {
+    let mut kept = Vec::with_capacity(plans.len());
+    for plan in plans.drain(..) {
+        let reason = if rsync::is_remote(&plan.target_path) {
+            Some("remote host".to_string())
+        } else {
+            // The target may not exist yet; check the filesystem it will be
+            // created on.
+            match plan.target_path.ancestors().find(|p| p.exists()) {
+                Some(existing) => network_filesystem(existing)?.map(str::to_string),
+                None => None,
+            }
+        };
+        match reason {
+            Some(reason) => {
+                if verbosity > Verbosity::Quiet {
+                    println!(
+                        "{}: target {:?} is not local ({}), skipping.",
+                        plan.submodule.name,
+                        display::path(&plan.target_path),
+                        reason
+                    );
+                }
+                summary.skipped += 1;
+                summary.record(&plan, SyncOutcome::NotLocal, None, 0, Duration::ZERO);
+            }
+            None => kept.push(plan),
+        }
+    }
+    *plans = kept;
+    Ok(())
+}
+
+/// Asks before each missing local target is created, dropping the plans whose
+/// target is declined. Answering "all" creates the remaining ones without
+/// asking.
+fn confirm_target_creation(
+    plans: &mut Vec<SyncPlan>,
+    verbosity: Verbosity,
+    summary: &mut SyncSummary,
+) -> io::Result<()> {
+    let mut all = false;
+    let mut kept = Vec::with_capacity(plans.len());
+    for plan in plans.drain(..) {
+        if all || rsync::is_remote(&plan.target_path) || plan.target_path.exists() {
+            kept.push(plan);
+            continue;
+        }
+        let target = std::path::absolute(&plan.target_path)?;
+        let question = format!(
+            "{}: create target directory {:?}?",
+            plan.submodule.name,
+            display::path(&target)
+        );
+        match prompt::choose(&question)? {
+            Choice::Yes => kept.push(plan),
+            Choice::All => {
+                all = true;
+                kept.push(plan);
+            }
+            Choice::No => {
+                if verbosity > Verbosity::Quiet {
+                    println!("{}: target not created, skipping.", plan.submodule.name);
+                }
+                summary.skipped += 1;
+                summary.record(&plan, SyncOutcome::TargetDeclined, None, 0, Duration::ZERO);
+            }
+            Choice::Abort => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "Sync cancelled by user",
+                ));
+            }
+        }
+    }
+    *plans = kept;
+    Ok(())
+}
+
+/// Names the network filesystem containing `path`, or `None` when it is local.
+#[cfg(target_os = "linux")]
+fn network_filesystem(path: &Path) -> io::Result<Option<&'static str>> {
+    use std::os::unix::ffi::OsStrExt;
+    // Magic numbers from linux/magic.h and the filesystems' own headers.
+    const NETWORK_FILESYSTEMS: &[(i64, &str)] = &[
+        (0x6969, "nfs"),
+        (0x517b, "smb"),
+        (0xfe53_4d42, "smb2"),
+        (0xff53_4d42, "cifs"),
+        (0x564c, "ncp"),
+        (0x5346_414f, "afs"),
+        (0x6b41_4653, "afs"),
+        (0x7375_7245, "coda"),
+        (0x0bd0_0bd0, "lustre"),
+        (0x0114_0114, "gpfs"),
+        (0x0102_1997, "9p"),
+        (0x00c3_6400, "ceph"),
+    ];
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stats` is valid for writes.
+    if unsafe { libc::statfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statfs succeeded, so it initialized `stats`.
+    let stats = unsafe { stats.assume_init() };
+    // f_type is signed on some architectures; compare the low 32 bits.
+    #[allow(clippy::unnecessary_cast)]
+    let fs_type = stats.f_type as i64 & 0xffff_ffff;
+    Ok(NETWORK_FILESYSTEMS
+        .iter()
+        .find(|(magic, _)| *magic == fs_type)
+        .map(|(_, name)| *name))
+}
+
+/// Without Linux's statfs types every local path counts as local.
+#[cfg(not(target_os = "linux"))]
+fn network_filesystem(_path: &Path) -> io::Result<Option<&'static str>> {
+    Ok(None)
+}
+
+/// Dry-runs every plan to capture the changes the sync is about to make and
+/// starts the audit record for this run.
+fn start_audit(
+    plans: &[SyncPlan],
+    options: &SyncOptions,
+    delete: bool,
+    config_dir: &Path,
+) -> io::Result<AuditRecord> {
+    let mut submodules = Vec::new();
+    for plan in plans {
+        let mut rsync_cmd = rsync_command(plan, options, delete);
+        rsync_cmd.arg("--dry-run").arg("--itemize-changes");
+        let (_, planned_changes) = rsync::run_captured(&rsync_cmd)?;
+        submodules.push(AuditEntry {
+            name: plan.submodule.name.clone(),
+            source: plan.source_path.clone(),
+            target: plan.target_path.clone(),
+            planned_changes,
+            outcome: "not run".to_string(),
+            exit_code: None,
+        });
+    }
+
+    Ok(AuditRecord {
+        timestamp: chrono::Local::now().to_rfc3339(),
+        tool_version: env!("CARGO_PKG_VERSION"),
+        config_hash: audit::config_hash(config_dir)?,
+        submodules,
+    })
+}
+
+/// Warns that `--move` deletes from the sources and asks for confirmation.
+fn confirm_move(plans: &[SyncPlan], yes: bool) -> io::Result<()> {
+    eprintln!("WARNING: --move deletes transferred files from these SOURCE directories:");
+    for plan in plans {
+        eprintln!("  {:?}", display::path(&plan.source_path));
+    }
+    if !prompt::confirm("Move files and delete them from the sources?", yes)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Sync cancelled by user",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `--pre-clean-target` empties `plan`'s target: remote targets and
+/// submodules that opted out of deletion are skipped.
+fn pre_cleans(plan: &SyncPlan) -> bool {
+    !rsync::is_remote(&plan.target_path) && plan.submodule.delete != Some(false)
+}
+
+/// Lists the targets `--pre-clean-target` will empty and asks for
+/// confirmation.
+fn confirm_pre_clean(plans: &[SyncPlan], yes: bool) -> io::Result<()> {
+    let targets: Vec<&SyncPlan> = plans
+        .iter()
+        .filter(|plan| pre_cleans(plan) && plan.target_path.is_dir())
+        .collect();
+    for plan in plans.iter().filter(|plan| !pre_cleans(plan)) {
+        eprintln!(
+            "Warning: --pre-clean-target skips '{}': its target is remote or has delete disabled.",
+            plan.submodule.name
+        );
+    }
+    if targets.is_empty() {
+        return Ok(());
+    }
+    eprintln!("WARNING: --pre-clean-target removes everything in these TARGET directories:");
+    for plan in &targets {
+        eprintln!("  {:?}", display::path(&plan.target_path));
+    }
+    if !prompt::confirm("Empty the targets before syncing?", yes)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Sync cancelled by user",
+        ));
+    }
+    Ok(())
+}
+
+/// Removes everything inside `dir`, leaving the directory itself. Symlinks
+/// are removed, never followed.
+fn clean_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the filesystem holding `plan`'s target keeps at least
+/// `min_free` bytes available after the transfer, whose size is estimated
+/// from a `--stats` dry-run. Remote targets cannot be checked and pass.
+fn has_free_space(
+    plan: &SyncPlan,
+    options: &SyncOptions,
+    delete: bool,
+    min_free: u64,
+) -> io::Result<bool> {
+    let name = &plan.submodule.name;
+    if rsync::is_remote(&plan.target_path) {
+        eprintln!(
+            "Warning: cannot check free space on remote target of '{}'.",
+            name
+        );
+        return Ok(true);
+    }
+    // The target may not exist yet; measure the filesystem it will be created on.
+    let Some(existing) = plan.target_path.ancestors().find(|p| p.exists()) else {
+        return Ok(true);
+    };
+    let available = available_space(existing)?;
+
+    let needed = match transfer_size(plan, options, delete)? {
+        Some(needed) => needed,
+        None => {
+            eprintln!(
+                "Warning: could not estimate the transfer size of '{}'; checking free space only.",
+                name
+            );
+            0
+        }
+    };
+
+    let required = needed.saturating_add(min_free);
+    if available < required {
+        eprintln!(
+            "{}: only {} free on {:?}, need {} ({} to transfer + {} minimum); skipping",
+            name,
+            format_size(available),
+            display::path(existing),
+            format_size(required),
+            format_size(needed),
+            format_size(min_free)
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Dry-runs `plan` with `--stats` and returns rsync's "Total transferred file
+/// size", or `None` when the dry-run failed.
+fn transfer_size(plan: &SyncPlan, options: &SyncOptions, delete: bool) -> io::Result<Option<u64>> {
+    let mut rsync_cmd = transfer_command(plan, options, delete);
+    rsync_cmd.arg("--dry-run").arg("--stats");
+    let (status, lines) = rsync::run_captured(&rsync_cmd)?;
+    if !status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        lines
+            .iter()
+            .find_map(|line| line.strip_prefix("Total transferred file size:"))
+            .and_then(|rest| {
+                rest.trim()
+                    .trim_end_matches("bytes")
+                    .trim()
+                    .replace(',', "")
+                    .parse()
+                    .ok()
+            })
+            .unwrap_or(0),
+    ))
+}
+
+/// Prints the `--estimate` of bytes each plan would transfer, the total and,
+/// under `--bwlimit`, how long that takes at the limit; then asks whether to
+/// go ahead.
+fn confirm_estimate(plans: &[SyncPlan], options: &SyncOptions, delete: bool) -> io::Result<()> {
+    let width = plans
+        .iter()
+        .map(|plan| plan.submodule.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut total = 0;
+    println!("Estimated transfer:");
+    for plan in plans {
+        match transfer_size(plan, options, delete)? {
+            Some(bytes) => {
+                total += bytes;
+                println!(
+                    "  {:width$}  {:>10}",
+                    plan.submodule.name,
+                    format_size(bytes),
+                    width = width
+                );
+            }
+            None => println!(
+                "  {:width$}  {:>10}",
+                plan.submodule.name,
+                "unknown",
+                width = width
+            ),
+        }
+    }
+    println!(
+        "  {:width$}  {:>10}",
+        "total",
+        format_size(total),
+        width = width
+    );
+    if let Some(rate) = options.bwlimit {
+        let seconds = total.div_ceil(rate);
+        println!(
+            "At --bwlimit {}/s this takes about {}m{:02}s.",
+            format_size(rate),
+            seconds / 60,
+            seconds % 60
+        );
+    }
+    if !prompt::confirm("Proceed with the sync?", options.yes)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Sync cancelled by user",
+        ));
+    }
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem containing `path`.
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stats` is valid for writes.
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs succeeded, so it initialized `stats`.
+    let stats = unsafe { stats.assume_init() };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Returns the `owner:group` argument for `chown`, or `None` when no
+/// ownership change was requested or the process cannot perform it.
+fn ownership_spec(options: &SyncOptions) -> Option<String> {
+    if options.target_owner.is_none() && options.target_group.is_none() {
+        return None;
+    }
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!(
+            "Warning: --target-owner/--target-group require root; skipping ownership changes."
+        );
+        return None;
+    }
+
+    let mut spec = options.target_owner.clone().unwrap_or_default();
+    if let Some(group) = &options.target_group {
+        spec.push(':');
+        spec.push_str(group);
+    }
+    Some(spec)
+}
+
+/// Recursively changes the ownership of `target`, returning whether it
+/// succeeded.
+fn change_owner(spec: &str, target: &Path) -> io::Result<bool> {
+    if rsync::is_remote(target) {
+        eprintln!(
+            "Warning: cannot chown remote target {:?}.",
+            display::path(target)
+        );
+        return Ok(true);
+    }
+    let status = ProcessCommand::new("chown")
+        .arg("-R")
+        .arg(spec)
+        .arg(target)
+        .status()?;
+    if !status.success() {
+        eprintln!(
+            "Failed to chown {:?} to {}: chown exited with {}",
+            display::path(target),
+            spec,
+            status
+        );
+    }
+    Ok(status.success())
+}
+
+/// Prints rsync's `--list-only` output for each plan, grouped by submodule.
+fn list_files(
+    plans: &[SyncPlan],
+    options: &SyncOptions,
+    summary: &mut SyncSummary,
+) -> io::Result<()> {
+    for plan in plans {
+        let mut rsync_cmd = rsync_command(plan, options, false);
+        rsync_cmd.list_only();
+        let (status, lines) = rsync::run_captured(&rsync_cmd)?;
+
+        println!("{}:", plan.submodule.name);
+        for line in &lines {
+            println!("  {}", line);
+        }
+        if status.success() {
+            summary.synced += 1;
+        } else {
+            eprintln!(
+                "Failed to list files for {}: rsync exited with {}",
+                plan.submodule.name, status
+            );
+            summary.failed += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the regular files `plan`'s filters select, relative to its source,
+/// as rsync's `--list-only` reports them.
+pub fn selected_files(plan: &SyncPlan, options: &SyncOptions) -> io::Result<Vec<String>> {
+    let mut rsync_cmd = rsync_command(plan, options, false);
+    rsync_cmd.list_only();
+    let (status, lines) = rsync::run_captured(&rsync_cmd)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Failed to list files for {}: rsync exited with {}",
+            plan.submodule.name, status
+        )));
+    }
+    Ok(lines
+        .iter()
+        .filter_map(|line| rsync::parse_list_only_file(line))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Repoints the `current` symlink of a `--target-template` layout at the
+/// release just synced. The new link is created beside the old one and
+/// renamed over it, so `current` never disappears.
+fn update_current_link(
+    root: &Path,
+    options: &SyncOptions,
+    submodule: &str,
+    stamp: &chrono::DateTime<chrono::Local>,
+) -> io::Result<()> {
+    let Some(template) = &options.target_template else {
+        return Ok(());
+    };
+    let Some((link, release)) = template.current_link(submodule, stamp) else {
+        eprintln!("Warning: --current-link needs a {{date}}, {{time}} or {{timestamp}} placeholder; no link created.");
+        return Ok(());
+    };
+    let link = root.join(link);
+    if let Ok(metadata) = fs::symlink_metadata(&link) {
+        if !metadata.file_type().is_symlink() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a symlink", display::path(&link)),
+            ));
+        }
+    }
+    let staged = link.with_file_name("current.new");
+    if fs::symlink_metadata(&staged).is_ok() {
+        fs::remove_file(&staged)?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&release, &staged)?;
+    #[cfg(not(unix))]
+    return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--current-link is only supported on Unix",
+    ));
+    fs::rename(&staged, &link)?;
+    if options.verbosity > Verbosity::Quiet {
+        println!(
+            "Updated {:?} -> {:?}",
+            display::path(&link),
+            display::path(&release)
+        );
+    }
+    Ok(())
+}
+
+/// Runs each plan's transfer command with `--dry-run` and prints the changes
+/// it would make. Submodules with pending changes count as changed.
+fn dry_run(
+    plans: &[SyncPlan],
+    options: &SyncOptions,
+    delete: bool,
+    summary: &mut SyncSummary,
+) -> io::Result<()> {
+    let runs = dry_run_all(plans, options, delete, options.jobs.unwrap_or(1));
+    for (plan, run) in plans.iter().zip(runs) {
+        let name = &plan.submodule.name;
+        let (status, lines, elapsed) = run?;
+        if !status.success() {
+            eprintln!("Failed to dry-run {}: rsync exited with {}", name, status);
+            summary.failed += 1;
+            summary.record(plan, SyncOutcome::Failed, status.code(), 0, elapsed);
+            continue;
+        }
+
+        let changes = change_lines(&lines, options);
+        if changes.is_empty() {
+            println!("{}: in sync", name);
+        } else {
+            println!("{}: {} change(s) pending", name, changes.len());
+            if !options.quiet_dry_run {
+                for line in &changes {
+                    println!("  {}", line);
+                }
+            }
+            if options.context && !rsync::is_remote(&plan.target_path) {
+                print_change_context(&plan.target_path, &changes)?;
+            }
+            summary.changed += 1;
+        }
+        summary.synced += 1;
+        summary.record(
+            plan,
+            SyncOutcome::DryRun,
+            status.code(),
+            changes.len(),
+            elapsed,
+        );
+    }
+    println!(
+        "Dry run: {} of {} submodule(s) would change; nothing was modified.",
+        summary.changed,
+        plans.len()
+    );
+    Ok(())
+}
+
+/// Runs the dry-run rsync of every plan, up to `jobs` at a time, and returns
+/// each one's status, output and duration in plan order. Dry-runs write
+/// nothing, so targets on the same filesystem are not serialized.
+fn dry_run_all(
+    plans: &[SyncPlan],
+    options: &SyncOptions,
+    delete: bool,
+    jobs: usize,
+) -> Vec<io::Result<(ExitStatus, Vec<String>, Duration)>> {
+    let run = |plan: &SyncPlan| {
+        let started = Instant::now();
+        let mut rsync_cmd = transfer_command(plan, options, delete);
+        rsync_cmd.arg("--dry-run");
+        rsync::run_captured(&rsync_cmd).map(|(status, lines)| (status, lines, started.elapsed()))
+    };
+    if jobs <= 1 {
+        return plans.iter().map(run).collect();
+    }
+    let queue = Mutex::new(plans.iter().enumerate());
+    let results = Mutex::new(BTreeMap::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(plans.len()) {
+            scope.spawn(|| loop {
+                let Some((index, plan)) = queue.lock().expect("dry-run worker panicked").next()
+                else {
+                    break;
+                };
+                let result = run(plan);
+                results
+                    .lock()
+                    .expect("dry-run worker panicked")
+                    .insert(index, result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .expect("dry-run worker panicked")
+        .into_values()
+        .collect()
+}
+
+/// Number of existing entries named per directory by `--context`.
+const CONTEXT_ENTRIES: usize = 5;
+
+/// For each directory touched by `changes`, prints what the target already
+/// holds there besides the changed entries.
+fn print_change_context(target: &Path, changes: &[&String]) -> io::Result<()> {
+    let mut by_dir: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for line in changes {
+        let Some((_, path)) = rsync::parse_itemized(line) else {
+            continue;
+        };
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        by_dir.entry(dir).or_default().push(name);
+    }
+
+    println!("  context:");
+    for (dir, changed) in by_dir {
+        let label = if dir.is_empty() {
+            "./".to_string()
+        } else {
+            format!("{}/", dir)
+        };
+        let target_dir = target.join(dir);
+        if !target_dir.is_dir() {
+            println!("    {}: new directory, {} change(s)", label, changed.len());
+            continue;
+        }
+        let mut others = Vec::new();
+        for entry in fs::read_dir(&target_dir)? {
+            let entry = entry?;
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if changed.contains(&name.as_str()) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                name.push('/');
+            }
+            others.push(name);
+        }
+        others.sort();
+        let mut listing = others
+            .iter()
+            .take(CONTEXT_ENTRIES)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if others.len() > CONTEXT_ENTRIES {
+            listing.push_str(&format!(", ... ({} more)", others.len() - CONTEXT_ENTRIES));
+        }
+        match others.len() {
+            0 => println!(
+                "    {}: {} change(s); nothing else there",
+                label,
+                changed.len()
+            ),
+            n => println!(
+                "    {}: {} change(s); already has {} other entr{}: {}",
+                label,
+                changed.len(),
+                n,
+                if n == 1 { "y" } else { "ies" },
+                listing
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Prints each plan's transfer command with a description of every argument.
+fn explain(plans: &[SyncPlan], options: &SyncOptions, delete: bool) {
+    for plan in plans {
+        println!("{}:", plan.submodule.name);
+        let explained = transfer_command(plan, options, delete).explain();
+        let width = explained
+            .iter()
+            .map(|(arg, _)| arg.len())
+            .max()
+            .unwrap_or(0);
+        for (arg, description) in &explained {
+            println!("  {:width$}  {}", arg, description, width = width);
+        }
+    }
+}
+
+/// Dry-runs each plan with `--debug=FILTER` and prints, grouped by the rule
+/// responsible, the paths its filters excluded.
+fn debug_filters(plans: &[SyncPlan], options: &SyncOptions, delete: bool) -> io::Result<()> {
+    for plan in plans {
+        let name = &plan.submodule.name;
+        let mut rsync_cmd = rsync_command(plan, options, delete);
+        rsync_cmd.arg("--dry-run").arg("--debug=FILTER");
+        let (status, lines) = rsync::run_captured(&rsync_cmd)?;
+        if !status.success() {
+            eprintln!(
+                "Failed to debug filters of {}: rsync exited with {}",
+                name, status
+            );
+            continue;
+        }
+
+        let mut by_pattern: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for line in &lines {
+            if let Some((path, pattern)) = rsync::parse_filter_exclusion(line) {
+                by_pattern.entry(pattern).or_default().push(path);
+            }
+        }
+        if by_pattern.is_empty() {
+            println!("{}: nothing excluded", name);
+            continue;
+        }
+        println!("{}:", name);
+        for (pattern, paths) in &by_pattern {
+            println!("  excluded by '{}' ({} path(s)):", pattern, paths.len());
+            for path in paths {
+                println!("    {}", path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Dry-runs `plan` with `--delete` and returns the target paths that would be
+/// deleted.
+fn planned_deletions(plan: &SyncPlan, options: &SyncOptions) -> io::Result<Vec<String>> {
+    let mut rsync_cmd = rsync_command(plan, options, true);
+    rsync_cmd.arg("--dry-run").arg("--itemize-changes");
+    let (status, lines) = rsync::run_captured(&rsync_cmd)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Deletion check for {} failed: rsync exited with {}",
+            plan.submodule.name, status
+        )));
+    }
+    Ok(lines
+        .iter()
+        .filter_map(|line| match rsync::parse_itemized(line) {
+            Some((ItemChange::Deleted, path)) => Some(path.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The deletion share `--preflight` allows without `--confirm-delete-threshold`.
+const PREFLIGHT_DELETE_THRESHOLD: f64 = 50.0;
+
+/// Runs the `--preflight` checks over `plans` and prints each result and the
+/// verdict. Fails on NO-GO unless `--force` was given.
+fn preflight(plans: &[SyncPlan], options: &SyncOptions) -> io::Result<()> {
+    let rsync_problems = match rsync::check_available() {
+        Ok(()) => Vec::new(),
+        Err(e) => vec![e.to_string()],
+    };
+    let rsync_ok = rsync_problems.is_empty();
+    let mut checks = vec![
+        ("rsync is installed", rsync_problems),
+        ("sources exist", Vec::new()),
+        ("targets are writable", Vec::new()),
+        ("no paths overlap", Vec::new()),
+        ("deletions are within the threshold", Vec::new()),
+    ];
+
+    for plan in plans {
+        let name = &plan.submodule.name;
+        if !plan.source_path.is_dir() {
+            checks[1].1.push(format!(
+                "{}: {:?} is not a directory",
+                name,
+                display::path(&plan.source_path)
+            ));
+        }
+        if !rsync::is_remote(&plan.target_path) {
+            // The target may not exist yet; it must be creatable then.
+            let writable = plan
+                .target_path
+                .ancestors()
+                .find(|p| p.exists())
+                .is_some_and(is_writable);
+            if !writable {
+                checks[2].1.push(format!(
+                    "{}: {:?} is not writable",
+                    name,
+                    display::path(&plan.target_path)
+                ));
+            }
+        }
+    }
+    checks[3].1 = path_overlaps(plans);
+
+    let threshold = options
+        .confirm_delete_threshold
+        .unwrap_or(PREFLIGHT_DELETE_THRESHOLD);
+    let deleting = !options.abort_on_delete;
+    // Without rsync the deletion dry-run cannot run; that is already a NO-GO.
+    if rsync_ok && deleting {
+        for plan in plans {
+            if !plan.source_path.is_dir() {
+                continue;
+            }
+            let Some((deleted, existing, percent)) = deletion_share(plan, options)? else {
+                continue;
+            };
+            if percent > threshold {
+                checks[4].1.push(format!(
+                    "{}: would delete {} of {} files ({:.0}%, limit {}%)",
+                    plan.submodule.name, deleted, existing, percent, threshold
+                ));
+            }
+        }
+    }
+
+    println!("Preflight:");
+    for (check, problems) in &checks {
+        let status = if problems.is_empty() { "ok" } else { "FAIL" };
+        println!("  {:<4}  {}", status, check);
+        for problem in problems {
+            println!("          {}", problem);
+        }
+    }
+    let failed = checks.iter().filter(|(_, p)| !p.is_empty()).count();
+    if failed == 0 {
+        println!("GO: all {} checks passed.", checks.len());
+        return Ok(());
+    }
+    println!("NO-GO: {} of {} checks failed.", failed, checks.len());
+    if options.force {
+        eprintln!("Warning: --force given; syncing despite the failed preflight checks.");
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "Preflight failed {} check(s); nothing was synced",
+        failed
+    )))
+}
+
+/// Returns whether the current user may create files in `path`.
+fn is_writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is NUL-terminated.
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Describes every pair of local paths that would interfere: a target inside
+/// a source (or the reverse), and targets that are equal or nested, where one
+/// sync's deletions would remove the other's files.
+fn path_overlaps(plans: &[SyncPlan]) -> Vec<String> {
+    let local = |path: &Path| !rsync::is_remote(path);
+    let mut overlaps = Vec::new();
+    for a in plans {
+        for b in plans {
+            let (a_name, b_name) = (&a.submodule.name, &b.submodule.name);
+            if local(&a.target_path) && a.target_path.starts_with(&b.source_path) {
+                overlaps.push(format!(
+                    "target of {} is inside the source of {}",
+                    a_name, b_name
+                ));
+            } else if local(&a.target_path) && b.source_path.starts_with(&a.target_path) {
+                overlaps.push(format!(
+                    "source of {} is inside the target of {}",
+                    b_name, a_name
+                ));
+            }
+        }
+    }
+    for (index, a) in plans.iter().enumerate() {
+        for b in &plans[index + 1..] {
+            let (a_name, b_name) = (&a.submodule.name, &b.submodule.name);
+            if a.target_path == b.target_path {
+                overlaps.push(format!("{} and {} share a target", a_name, b_name));
+            } else if local(&a.target_path)
+                && (a.target_path.starts_with(&b.target_path)
+                    || b.target_path.starts_with(&a.target_path))
+            {
+                overlaps.push(format!("targets of {} and {} are nested", a_name, b_name));
+            }
+        }
+    }
+    overlaps
+}
+
+/// Dry-runs `plan` and returns how many of the files already in its target a
+/// sync would delete, out of how many, and the percentage. `None` when the
+/// target has no files yet.
+fn deletion_share(
+    plan: &SyncPlan,
+    options: &SyncOptions,
+) -> io::Result<Option<(usize, usize, f64)>> {
+    if !plan.target_path.is_dir() {
+        return Ok(None);
+    }
+    let existing = verify::walk_relative(&plan.target_path)?
+        .iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .count();
+    if existing == 0 {
+        return Ok(None);
+    }
+    let deleted = planned_deletions(plan, options)?
+        .iter()
+        .filter(|path| !path.ends_with('/'))
+        .count();
+    Ok(Some((
+        deleted,
+        existing,
+        deleted as f64 * 100.0 / existing as f64,
+    )))
+}
+
+/// Dry-runs every plan and asks for confirmation when a sync would delete
+/// anything from a target, listing how many files each would lose.
+fn confirm_deletions(plans: &[SyncPlan], options: &SyncOptions) -> io::Result<()> {
+    let mut deleting = Vec::new();
+    for plan in plans {
+        let local = !rsync::is_remote(&plan.target_path);
+        if plan.submodule.delete == Some(false) || (local && !plan.target_path.is_dir()) {
+            continue;
+        }
+        let deleted = planned_deletions(plan, options)?.len();
+        if deleted > 0 {
+            deleting.push((plan, deleted));
+        }
+    }
+
+    if deleting.is_empty() {
+        return Ok(());
+    }
+    eprintln!("These syncs would delete files from their targets:");
+    for (plan, deleted) in &deleting {
+        eprintln!(
+            "  {}: {} path(s) in {:?}",
+            plan.submodule.name,
+            deleted,
+            display::path(&plan.target_path)
+        );
+    }
+    if !prompt::confirm("Proceed with these deletions?", options.yes)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Sync cancelled by user",
+        ));
+    }
+    Ok(())
+}
+
+/// Dry-runs every plan and asks for confirmation when a sync would delete
+/// more than `threshold` percent of the files already in a target.
+fn confirm_delete_threshold(
+    plans: &[SyncPlan],
+    options: &SyncOptions,
+    threshold: f64,
+) -> io::Result<()> {
+    let mut exceeded = Vec::new();
+    for plan in plans {
+        let Some((deleted, existing, percent)) = deletion_share(plan, options)? else {
+            continue;
+        };
+        if percent > threshold {
+            exceeded.push((plan, deleted, existing, percent));
+        }
+    }
+
+    if exceeded.is_empty() {
+        return Ok(());
+    }
+    eprintln!(
+        "WARNING: these syncs would delete more than {}% of their target's files:",
+        threshold
+    );
+    for (plan, deleted, existing, percent) in &exceeded {
+        eprintln!(
+            "  {}: {} of {} files ({:.0}%) in {:?}",
+            plan.submodule.name,
+            deleted,
+            existing,
+            percent,
+            display::path(&plan.target_path)
+        );
+    }
+    if !prompt::confirm("Proceed with these deletions?", options.yes)? {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Sync cancelled by user",
+        ));
+    }
+    Ok(())
+}
+
+/// Dry-runs every plan with `--delete` and fails if any target contains files
+/// that a deleting sync would remove.
+fn check_no_deletions(plans: &[SyncPlan], options: &SyncOptions) -> io::Result<()> {
+    let mut pending = Vec::new();
+    for plan in plans {
+        // A missing target has nothing to delete.
+        if !plan.target_path.is_dir() {
+            continue;
+        }
+
+        let deleted = planned_deletions(plan, options)?;
+        if !deleted.is_empty() {
+            pending.push((plan, deleted));
+        }
+    }
+
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("--abort-on-delete: the following files would be deleted:");
+    let mut total = 0;
+    for (plan, deleted) in &pending {
+        eprintln!(
+            "  {} ({:?}):",
+            plan.submodule.name,
+            display::path(&plan.target_path)
+        );
+        for path in deleted {
+            eprintln!("    {}", path);
+        }
+        total += deleted.len();
+    }
+    Err(io::Error::other(format!(
+        "Aborting sync: {} file(s) in {} target(s) would be deleted; resolve them manually before syncing",
+        total,
+        pending.len()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan<'a>(submodule: &'a SubmoduleConfig, archive: &'a ArchiveFlags) -> SyncPlan<'a> {
+        SyncPlan {
+            submodule,
+            archive,
+            global_include: &[],
+            global_exclude: &[],
+            build_artifacts: &[],
+            source_path: PathBuf::from("/repo/app"),
+            target_path: PathBuf::from("/deploy/app"),
+            config_dir_pattern: None,
+            strip_roots: Vec::new(),
+            source_rules: None,
+            content_matches: None,
+            target_unresolved: false,
+        }
+    }
+
+    fn filters(rsync_cmd: &RsyncCommand) -> Vec<String> {
+        rsync_cmd
+            .args()
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .filter(|arg| {
+                arg.starts_with("--include=")
+                    || arg.starts_with("--exclude=")
+                    || arg.starts_with("--filter=")
+            })
+            .collect()
+    }
+
+    fn stamp() -> chrono::DateTime<chrono::Local> {
+        use chrono::TimeZone;
+        chrono::Local
+            .with_ymd_and_hms(2026, 10, 15, 9, 8, 7)
+            .unwrap()
+    }
+
+    #[test]
+    fn target_template_rejects_unknown_and_unclosed_placeholders() {
+        assert!(TargetTemplate::parse("/srv/{submodule}/{date}").is_ok());
+        assert!(TargetTemplate::parse("").is_err());
+        assert!(TargetTemplate::parse("/srv/{name}")
+            .unwrap_err()
+            .contains("unknown placeholder '{name}'"));
+        assert!(TargetTemplate::parse("/srv/{submodule")
+            .unwrap_err()
+            .contains("unclosed"));
+    }
+
+    #[test]
+    fn target_template_expands_every_placeholder() {
+        let template = TargetTemplate::parse("/srv/{submodule}/{timestamp}-{time}-{date}").unwrap();
+        assert!(template.uses("submodule"));
+        assert!(!template.uses("stamp"));
+        assert_eq!(
+            template.expand("app", &stamp()),
+            Path::new("/srv/app/20261015T090807-090807-2026-10-15")
+        );
+    }
+
+    #[test]
+    fn current_link_sits_beside_the_first_timed_component() {
+        let template = TargetTemplate::parse("releases/{submodule}/{date}/www").unwrap();
+        assert_eq!(
+            template.current_link("app", &stamp()),
+            Some((
+                PathBuf::from("releases/app/current"),
+                PathBuf::from("2026-10-15")
+            ))
+        );
+        let untimed = TargetTemplate::parse("/srv/{submodule}").unwrap();
+        assert_eq!(untimed.current_link("app", &stamp()), None);
+    }
+
+    #[test]
+    fn targets_must_stay_within_the_target_base() {
+        let submodule = SubmoduleConfig::new("app");
+        let archive = ArchiveFlags::default();
+        let mut plans = vec![plan(&submodule, &archive)];
+        let base = Path::new("/srv/deploy");
+
+        plans[0].target_path = PathBuf::from("/srv/deploy/app");
+        assert!(check_targets_within_base(&plans, base).is_ok());
+        plans[0].target_path = PathBuf::from("/srv/deploy/../other/app");
+        let error = check_targets_within_base(&plans, base).unwrap_err();
+        assert!(error.to_string().contains("outside"), "{}", error);
+        // The monorepo's parent no longer counts once a base is configured.
+        plans[0].target_path = PathBuf::from("/repo-parent/app");
+        assert!(check_targets_within_base(&plans, base).is_err());
+        plans[0].target_path = PathBuf::from("host:/anywhere/app");
+        assert!(check_targets_within_base(&plans, base).is_ok());
+    }
+
+    #[test]
+    fn path_overlaps_reports_nested_and_shared_paths() {
+        let (app, web) = (SubmoduleConfig::new("app"), SubmoduleConfig::new("web"));
+        let archive = ArchiveFlags::default();
+        let mut plans = vec![plan(&app, &archive), plan(&web, &archive)];
+        plans[1].source_path = PathBuf::from("/repo/web");
+        plans[1].target_path = PathBuf::from("/deploy/web");
+        assert!(path_overlaps(&plans).is_empty());
+
+        plans[1].target_path = PathBuf::from("/deploy/app");
+        assert_eq!(path_overlaps(&plans), ["app and web share a target"]);
+
+        plans[1].target_path = PathBuf::from("/deploy/app/web");
+        assert_eq!(path_overlaps(&plans), ["targets of app and web are nested"]);
+
+        plans[1].target_path = PathBuf::from("/repo/app/out");
+        assert_eq!(
+            path_overlaps(&plans),
+            ["target of web is inside the source of app"]
+        );
+    }
+
+    #[test]
+    fn max_depth_rule_comes_before_every_other_filter() {
+        assert_eq!(max_depth_rule(0), "--exclude=/*/");
+        assert_eq!(max_depth_rule(2), "--exclude=/*/*/*/");
+
+        let submodule = SubmoduleConfig::new("app");
+        let archive = ArchiveFlags::default();
+        let mut plan = plan(&submodule, &archive);
+        plan.content_matches = Some(vec!["lib/deep/nested/file.dart".to_string()]);
+        let options = SyncOptions {
+            max_depth: Some(1),
+            preserve_target: vec!["*.env".to_string()],
+            ..SyncOptions::default()
+        };
+        let filters = filters(&rsync_command(&plan, &options, true));
+        assert_eq!(filters[0], "--exclude=/*/*/");
+        assert!(filters.contains(&"--filter=P *.env".to_string()));
+        assert!(filters.contains(&"--include=/lib/deep/nested/file.dart".to_string()));
+    }
+
+    #[test]
+    fn delete_scope_rules_come_after_every_other_filter() {
+        let submodule = SubmoduleConfig::new("app");
+        let archive = ArchiveFlags::default();
+        let options = SyncOptions {
+            delete_scope: vec!["assets".to_string()],
+            exclude_ext: vec!["log".to_string()],
+            ..SyncOptions::default()
+        };
+        let scoped = filters(&rsync_command(&plan(&submodule, &archive), &options, true));
+        let position = |rule: &str| scoped.iter().position(|f| f == rule).unwrap();
+        assert!(position("--exclude=*.log") < position("--filter=R /assets/***"));
+        assert!(position("--filter=-s *") < position("--filter=R /assets/***"));
+        assert_eq!(scoped.last().unwrap(), "--filter=P *");
+        assert!(!scoped.contains(&"--exclude=*".to_string()));
+
+        // Without --delete the scope has nothing to limit.
+        let unscoped = filters(&rsync_command(&plan(&submodule, &archive), &options, false));
+        assert!(!unscoped.iter().any(|f| f.starts_with("--filter=R")));
+        assert!(unscoped.contains(&"--exclude=*".to_string()));
+    }
+
+    #[test]
+    fn bandwidth_shares_never_exceed_the_total() {
+        let budget = BandwidthBudget::new(1000, 3);
+        let shares = [budget.acquire(), budget.acquire(), budget.acquire()];
+        assert_eq!(shares, [333, 333, 334]);
+        budget.release(shares[0]);
+        assert_eq!(budget.acquire(), 333);
+
+        // Fewer bytes than workers: each share is at least 1 and the
+        // next transfer waits until one is returned.
+        let budget = BandwidthBudget::new(1, 3);
+        assert_eq!(budget.acquire(), 1);
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| budget.acquire());
+            budget.release(1);
+            assert_eq!(waiting.join().unwrap(), 1);
+        });
+        assert_eq!(*budget.held.lock().unwrap(), (1, 1));
+    }
+
+    #[test]
+    fn parse_size_accepts_binary_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size(" 3TB "), Ok(3 << 40));
+        assert_eq!(parse_size("18446744073709551615"), Ok(u64::MAX));
+        for invalid in [
+            "",
+            "M",
+            "1.5M",
+            "-1",
+            "10X",
+            "18446744073709551616",
+            "16777216T",
+        ] {
+            assert_eq!(
+                parse_size(invalid),
+                Err(format!("invalid size '{}'", invalid)),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+}
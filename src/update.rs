@@ -0,0 +1,183 @@
+use crate::audit;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command as ProcessCommand;
+
+/// Release metadata endpoint queried when no `--url` is given. The response
+/// is expected in the GitHub "latest release" format.
+pub const DEFAULT_RELEASE_URL: &str =
+    "https://api.github.com/repos/safvan-husain/mono/releases/latest";
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Parses a `1.2.3` or `v1.2.3` version into comparable numeric parts.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+/// Fetches `url` with curl and returns the response body, asking for JSON
+/// with `json` (the release metadata) and for any content otherwise.
+fn fetch(url: &str, json: bool) -> io::Result<Vec<u8>> {
+    let mut curl = ProcessCommand::new("curl");
+    curl.arg("-fsSL");
+    if json {
+        curl.args(["-H", "Accept: application/json"]);
+    }
+    let output = curl
+        .arg(url)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run curl: {}", e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Fetching {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Checks the release source for a newer version and, with `apply`, replaces
+/// the running binary with the matching release asset.
+pub fn check_for_update(url: &str, apply: bool) -> io::Result<()> {
+    let body = fetch(url, true)?;
+    let release: Release = serde_json::from_slice(&body).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected release data from {}: {}", url, e),
+        )
+    })?;
+
+    let current = env!("CARGO_PKG_VERSION");
+    let (Some(latest_version), Some(current_version)) =
+        (parse_version(&release.tag_name), parse_version(current))
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Cannot compare versions {} and {}",
+                release.tag_name, current
+            ),
+        ));
+    };
+
+    if latest_version <= current_version {
+        println!("monorepo-agent {} is up to date.", current);
+        return Ok(());
+    }
+
+    println!(
+        "A newer version is available: {} (current {}).",
+        release.tag_name, current
+    );
+    if !apply {
+        println!("Run 'monorepo-agent update --apply' to install it.");
+        return Ok(());
+    }
+
+    let asset_name = format!(
+        "monorepo-agent-{}-{}{}",
+        env::consts::OS,
+        env::consts::ARCH,
+        env::consts::EXE_SUFFIX
+    );
+    let find_asset = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Release {} has no asset named {}", release.tag_name, name),
+                )
+            })
+    };
+    let asset = find_asset(&asset_name)?;
+    let checksum_asset = find_asset(&format!("{}.sha256", asset_name))?;
+
+    let binary = fetch(&asset.browser_download_url, false)?;
+    let checksum = fetch(&checksum_asset.browser_download_url, false)?;
+    verify_checksum(&binary, &String::from_utf8_lossy(&checksum))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", asset_name, e)))?;
+    replace_current_exe(&binary)?;
+    println!("Updated monorepo-agent to {}.", release.tag_name);
+    Ok(())
+}
+
+/// Checks `binary` against a published `sha256sum` line, whose first field
+/// is the hex digest.
+fn verify_checksum(binary: &[u8], published: &str) -> io::Result<()> {
+    let expected = published.split_whitespace().next().unwrap_or_default();
+    let actual = audit::hex(&Sha256::digest(binary));
+    if !expected.eq_ignore_ascii_case(&actual) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch: published {:?}, downloaded {}",
+                expected, actual
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes `binary` next to the running executable and renames it into place,
+/// so a failed download never leaves a half-written binary behind.
+fn replace_current_exe(binary: &[u8]) -> io::Result<()> {
+    let current_exe = env::current_exe()?;
+    let staged = current_exe.with_extension("update");
+    fs::write(&staged, binary)?;
+    let installed = make_executable(&staged).and_then(|()| fs::rename(&staged, &current_exe));
+    if installed.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    installed
+}
+
+fn make_executable(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_checksum_reads_the_first_field() {
+        let digest = audit::hex(&Sha256::digest(b"binary"));
+        let published = format!("{}  monorepo-agent-linux-x86_64\n", digest);
+        assert!(verify_checksum(b"binary", &published).is_ok());
+        assert!(verify_checksum(b"binary", &digest.to_uppercase()).is_ok());
+        let error = verify_checksum(b"tampered", &published).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(verify_checksum(b"binary", "").is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use crate::audit;
+use crate::config;
+use crate::display;
+use crate::pattern;
+use crate::rsync;
+use crate::sync;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Problems found for one submodule. Errors would break a sync; warnings
+/// point at config that is likely stale.
+#[derive(Default)]
+struct Findings {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+/// Cross-checks the config against the directory structure: sources must
+/// exist, include patterns should match something, and names must be unique
+/// and filesystem-safe. Fails if any error was found, or with `strict` (the
+/// `validate` command) if anything at all was found.
+pub fn verify_config(strict: bool) -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+
+    if app_config.submodules.is_empty() {
+        println!("No submodules configured.");
+        return Ok(());
+    }
+
+    let plans = sync::plan_submodules(&root, &app_config, None)?;
+    let mut seen = HashSet::new();
+    let (mut errors, mut warnings) = (0, 0);
+    for plan in &plans {
+        let name = &plan.submodule.name;
+        let mut findings = Findings::default();
+        if !seen.insert(name) {
+            findings.errors.push("duplicate submodule name".to_string());
+        }
+        if let Err(reason) = config::validate_name(name) {
+            findings.errors.push(format!("unsafe name: {}", reason));
+        }
+
+        if plan.submodule.include().is_empty() {
+            findings
+                .warnings
+                .push("no include patterns; everything not excluded will be synced".to_string());
+        }
+        if plan.submodule.exclude().is_empty() {
+            findings
+                .warnings
+                .push(match plan.submodule.include().is_empty() {
+                    true => "no exclude patterns".to_string(),
+                    false => "no exclude patterns; include patterns have no effect without one"
+                        .to_string(),
+                });
+        }
+        if !plan.source_path.is_dir() {
+            findings.errors.push(format!(
+                "source path {:?} does not exist or is not a directory",
+                display::path(&plan.source_path)
+            ));
+        } else if !plan.submodule.include().is_empty() {
+            let entries = walk_relative(&plan.source_path)?;
+            for include in plan.submodule.include() {
+                let matched = entries
+                    .iter()
+                    .any(|(path, is_dir)| pattern::matches(include, path, *is_dir));
+                if !matched {
+                    findings
+                        .warnings
+                        .push(format!("include pattern '{}' matches nothing", include));
+                }
+            }
+        }
+
+        if findings.errors.is_empty() && findings.warnings.is_empty() {
+            println!("{}: OK", name);
+            continue;
+        }
+        println!("{}:", name);
+        for error in &findings.errors {
+            println!("  error: {}", error);
+        }
+        for warning in &findings.warnings {
+            println!("  warning: {}", warning);
+        }
+        errors += findings.errors.len();
+        warnings += findings.warnings.len();
+    }
+
+    if errors == 0 && warnings == 0 {
+        println!("Config OK");
+        return Ok(());
+    }
+    println!("{} error(s), {} warning(s).", errors, warnings);
+    if errors > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Config verification found {} error(s)", errors),
+        ));
+    }
+    if strict {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Config validation found {} warning(s)", warnings),
+        ));
+    }
+    Ok(())
+}
+
+/// Validates the config file alone, for pre-commit hooks: it must parse,
+/// names must be unique and filesystem-safe, and every submodule needs
+/// include patterns. Sources and targets are never touched.
+pub fn lint_config() -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+
+    let mut seen = HashSet::new();
+    let mut errors = Vec::new();
+    for submodule in &app_config.submodules {
+        let name = &submodule.name;
+        if !seen.insert(name) {
+            errors.push(format!("{}: duplicate submodule name", name));
+        }
+        if let Err(reason) = config::validate_name(name) {
+            errors.push(format!("{}: unsafe name: {}", name, reason));
+        }
+        if let Some(Err(reason)) = submodule
+            .target_subdir
+            .as_deref()
+            .map(config::validate_subdir)
+        {
+            errors.push(format!("{}: {}", name, reason));
+        }
+        if submodule.include().is_empty() {
+            errors.push(format!(
+                "{}: no include patterns; everything not excluded will be synced",
+                name
+            ));
+        }
+    }
+
+    for error in &errors {
+        println!("error: {}", error);
+    }
+    if !errors.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Config lint found {} error(s)", errors.len()),
+        ));
+    }
+    println!("{} submodule(s) OK.", app_config.submodules.len());
+    Ok(())
+}
+
+/// How one selected source file compares with its copy in the target.
+enum FileCheck {
+    Match,
+    Missing,
+    Differs,
+    Unreadable(io::Error),
+}
+
+/// Checks that each selected submodule's target holds every source file its
+/// filters select, with the same size or, with `checksum`, the same SHA-256.
+/// Files are compared on all cores. Fails if any file is missing or differs.
+pub fn verify_targets(selected: Option<&[String]>, checksum: bool) -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+    rsync::check_available()?;
+
+    let mut problems = 0;
+    for plan in sync::plan_submodules(&root, &app_config, selected)? {
+        let name = &plan.submodule.name;
+        if rsync::is_remote(&plan.target_path) {
+            eprintln!("Warning: cannot verify the remote target of '{}'.", name);
+            continue;
+        }
+        if plan.target_unresolved {
+            eprintln!(
+                "Warning: cannot verify '{}', whose target comes from target_command.",
+                name
+            );
+            continue;
+        }
+        if !plan.source_path.is_dir() {
+            eprintln!(
+                "{}: source {:?} does not exist",
+                name,
+                display::path(&plan.source_path)
+            );
+            problems += 1;
+            continue;
+        }
+        let files = sync::selected_files(&plan, &sync::SyncOptions::default())?;
+        let checks = check_files(&plan.source_path, &plan.target_path, &files, checksum);
+        let mut failed = Vec::new();
+        for (path, check) in files.iter().zip(checks) {
+            match check {
+                FileCheck::Match => {}
+                FileCheck::Missing => failed.push(format!("missing: {}", path)),
+                FileCheck::Differs => failed.push(format!("differs: {}", path)),
+                FileCheck::Unreadable(e) => failed.push(format!("unreadable: {} ({})", path, e)),
+            }
+        }
+        if failed.is_empty() {
+            println!("{}: {} file(s) OK", name, files.len());
+            continue;
+        }
+        println!(
+            "{}: {} of {} file(s) do not match",
+            name,
+            failed.len(),
+            files.len()
+        );
+        for line in &failed {
+            println!("  {}", line);
+        }
+        problems += failed.len();
+    }
+
+    if problems > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Target verification found {} problem(s)", problems),
+        ));
+    }
+    Ok(())
+}
+
+/// Compares each of `files` in `source` with the same path in `target`,
+/// spreading the work over one thread per core. Results are in `files` order.
+fn check_files(source: &Path, target: &Path, files: &[String], checksum: bool) -> Vec<FileCheck> {
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(files.len());
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<FileCheck>>> = Mutex::new(files.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = files.get(index) else {
+                    break;
+                };
+                let check = check_file(&source.join(path), &target.join(path), checksum);
+                results.lock().expect("verify worker panicked")[index] = Some(check);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .expect("verify worker panicked")
+        .into_iter()
+        .map(|check| check.expect("every file is checked"))
+        .collect()
+}
+
+fn check_file(source: &Path, target: &Path, checksum: bool) -> FileCheck {
+    let target_metadata = match fs::metadata(target) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return FileCheck::Missing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return FileCheck::Missing,
+        Err(e) => return FileCheck::Unreadable(e),
+    };
+    let result = fs::metadata(source).and_then(|source_metadata| {
+        if source_metadata.len() != target_metadata.len() {
+            return Ok(false);
+        }
+        if !checksum {
+            return Ok(true);
+        }
+        Ok(file_hash(source)? == file_hash(target)?)
+    });
+    match result {
+        Ok(true) => FileCheck::Match,
+        Ok(false) => FileCheck::Differs,
+        Err(e) => FileCheck::Unreadable(e),
+    }
+}
+
+/// Hex-encoded SHA-256 of the file at `path`, read in chunks.
+fn file_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(audit::hex(&hasher.finalize()))
+}
+
+/// Lists every file and directory under `root` as a `/`-separated path
+/// relative to it, paired with whether it is a directory. Symlinks are listed
+/// but not followed.
+pub fn walk_relative(root: &Path) -> io::Result<Vec<(String, bool)>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = if relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", relative, name)
+            };
+            let is_dir = entry.file_type()?.is_dir();
+            if is_dir {
+                pending.push(path.clone());
+            }
+            entries.push((path, is_dir));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_files_compares_size_or_content() {
+        let scratch =
+            std::env::temp_dir().join(format!("monorepo-agent-verify-{}", std::process::id()));
+        let (source, target) = (scratch.join("src"), scratch.join("dest"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(target.join("dir.txt")).unwrap();
+        for (name, contents) in [
+            ("same.txt", "abc"),
+            ("edited.txt", "abc"),
+            ("resized.txt", "abc"),
+        ] {
+            fs::write(source.join(name), contents).unwrap();
+        }
+        fs::write(source.join("dir.txt"), "abc").unwrap();
+        fs::write(source.join("missing.txt"), "abc").unwrap();
+        fs::write(target.join("same.txt"), "abc").unwrap();
+        fs::write(target.join("edited.txt"), "abd").unwrap();
+        fs::write(target.join("resized.txt"), "abcd").unwrap();
+        // Present in the target but gone from the source.
+        fs::write(target.join("vanished.txt"), "abc").unwrap();
+
+        let files: Vec<String> = [
+            "same.txt",
+            "edited.txt",
+            "resized.txt",
+            "missing.txt",
+            "dir.txt",
+            "vanished.txt",
+        ]
+        .map(String::from)
+        .to_vec();
+        let summarize = |checks: Vec<FileCheck>| -> Vec<&str> {
+            checks
+                .iter()
+                .map(|check| match check {
+                    FileCheck::Match => "match",
+                    FileCheck::Missing => "missing",
+                    FileCheck::Differs => "differs",
+                    FileCheck::Unreadable(_) => "unreadable",
+                })
+                .collect()
+        };
+        assert_eq!(
+            summarize(check_files(&source, &target, &files, false)),
+            [
+                "match",
+                "match",
+                "differs",
+                "missing",
+                "missing",
+                "unreadable"
+            ]
+        );
+        assert_eq!(
+            summarize(check_files(&source, &target, &files, true)),
+            [
+                "match",
+                "differs",
+                "differs",
+                "missing",
+                "missing",
+                "unreadable"
+            ]
+        );
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+}
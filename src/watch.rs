@@ -0,0 +1,89 @@
+use crate::config;
+use crate::display;
+use crate::state;
+use crate::sync::{self, SyncOptions, Verbosity};
+use clap::ValueEnum;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What `watch` does when it starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum InitialSync {
+    /// Sync every watched submodule first, so targets start consistent.
+    #[default]
+    Full,
+    /// Wait for the first change.
+    None,
+}
+
+/// Polls the sources of the selected submodules every `interval` and syncs
+/// each one whose files changed since the previous poll. Runs until
+/// interrupted; a failed sync is reported and watching continues.
+pub fn watch(
+    selected: Option<&[String]>,
+    options: &SyncOptions,
+    interval: Duration,
+    initial: InitialSync,
+) -> io::Result<()> {
+    let root = config::find_root()?;
+    let config_dir = config::require_config_dir(&root)?;
+    let app_config = config::load_or_create_config(&config_dir)?;
+    let sources: Vec<(String, PathBuf)> = sync::plan_submodules(&root, &app_config, selected)?
+        .into_iter()
+        .map(|plan| (plan.submodule.name.clone(), plan.source_path))
+        .collect();
+    if sources.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No submodules to watch",
+        ));
+    }
+
+    let mut since = state::now_secs();
+    if initial == InitialSync::Full {
+        let names: Vec<String> = sources.iter().map(|(name, _)| name.clone()).collect();
+        run(&names, options);
+    }
+    if options.verbosity > Verbosity::Quiet {
+        println!(
+            "Watching {} submodule(s) every {}s; press Ctrl-C to stop.",
+            sources.len(),
+            interval.as_secs()
+        );
+    }
+    loop {
+        std::thread::sleep(interval);
+        // Taken before scanning, so changes made during a sync are seen by
+        // the next poll.
+        let now = state::now_secs();
+        let mut changed = Vec::new();
+        for (name, source) in &sources {
+            match state::modified_since(source, since) {
+                Ok(true) => changed.push(name.clone()),
+                Ok(false) => {}
+                Err(e) => eprintln!(
+                    "Cannot scan {:?} of '{}': {}",
+                    display::path(source),
+                    name,
+                    e
+                ),
+            }
+        }
+        since = now;
+        if !changed.is_empty() {
+            run(&changed, options);
+        }
+    }
+}
+
+/// Syncs `names`, reporting failures without stopping the watch.
+fn run(names: &[String], options: &SyncOptions) {
+    match sync::sync_submodules(Some(names), options) {
+        Ok(summary) if summary.failed > 0 => {
+            eprintln!("{} submodule(s) failed to sync.", summary.failed)
+        }
+        Ok(_) => {}
+        Err(aborted) => eprintln!("Error: {}", aborted.error),
+    }
+}